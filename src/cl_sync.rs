@@ -1,24 +1,60 @@
 use anyhow::{anyhow, Result};
+use chrono::Local;
 use dialoguer::Input;
+use hashbrown::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::exit;
+use tokio::time::Instant;
 use tracing::debug;
 
+use crate::error::{ClSyncError, RcError, RcloneError, TomlError};
+use crate::operations::rc;
 use crate::operations::rclone;
-use crate::operations::rclone::RcloneServer;
 use crate::operations::sys_ops;
 use crate::operations::toml;
 
+pub mod archive;
+pub mod bwlimit;
 pub mod cache;
+pub mod cache_commands;
+pub mod daemon;
+pub mod file_upload;
+pub mod lock;
+pub mod quota;
+pub mod rclone_options;
+pub mod retry;
+pub mod schedule;
+pub mod service;
+pub mod session;
+pub mod snapshot;
+pub mod status;
+pub mod summary;
+pub mod watch;
+
+pub use session::SyncSession;
+pub use summary::{CloudOutcome, EntryOutcome, SyncSummary};
+
+// Outcome of an entry per cloud
+pub type CloudResults = Vec<(String, std::result::Result<(), ClSyncError>)>;
+
+pub async fn sync_config(parsed_toml: &toml::TomlParser) -> Result<toml::SyncConfig> {
+    match parsed_toml
+        .get_section_from_toml(toml::TomlSection::Sync)
+        .await
+    {
+        Ok(toml::TomlToParse::Sync(sync_config)) => Ok(sync_config),
+        _ => Err(anyhow!("Unexpected section type for sync")),
+    }
+}
 
 pub async fn check_last_update() {
-    todo!();
+    eprintln!("--check is not implemented yet.");
 }
 
 // if mode is set to interactive
 // interactive mode is set to true by default pass --nointe to disable
-pub async fn interactive_mode_to_up(nointe: bool, path: &PathBuf) -> Result<bool> {
+pub async fn interactive_mode_to_up(nointe: bool, path: &Path) -> Result<bool> {
     if !nointe {
         let dir_or_file = if sys_ops::is_dir(path.to_path_buf()).await? {
             "directory"
@@ -39,13 +75,12 @@ pub async fn interactive_mode_to_up(nointe: bool, path: &PathBuf) -> Result<bool
                 "yes" | "y" | "true" | "1" | "no" | "n" | "false" | "0" => Ok(()),
                 _ => Err(anyhow!("Please enter 'yes/1' or 'no/0'")),
             })
-            .interact_text()
-            .unwrap()
+            .interact_text()?
             .to_lowercase();
         let over = matches!(over.as_str(), "yes" | "y" | "true" | "1");
         return Ok(over);
     }
-    return Ok(false);
+    Ok(false)
 }
 
 pub async fn begin_upload(
@@ -53,7 +88,7 @@ pub async fn begin_upload(
     path: PathBuf,
     nointe: bool,
 ) -> Result<()> {
-    let reupload_again = interactive_mode_to_up(nointe, &path).await.unwrap();
+    let reupload_again = interactive_mode_to_up(nointe, &path).await?;
     debug!("Reupload again: {reupload_again}");
     if !reupload_again {
         // call sync opperation
@@ -68,245 +103,647 @@ pub async fn begin_upload(
     Ok(())
 }
 
-pub async fn begin_sync(parsed_toml: &toml::TomlParser) -> Result<()> {
-    let cache = cache::load(&parsed_toml).await.unwrap();
-    let mut rclone_server: Option<RcloneServer> = None;
-
+// Sync every entry. By default a failing entry doesn't stop the others,
+// with fail_fast the entries after the first failure are left alone.
+pub async fn begin_sync(parsed_toml: &toml::TomlParser, fail_fast: bool) -> Result<()> {
     let upload_list = match parsed_toml
         .get_section_from_toml(toml::TomlSection::Upload)
         .await
     {
         Ok(toml::TomlToParse::Upload(dir)) => dir,
-        _ => return Err(anyhow::anyhow!("Unexpected section type for upload list")),
+        Ok(_) => return Err(anyhow!("Unexpected section type for upload list")),
+        Err(e) => return Err(e),
     };
 
-    let mut mounted_remotes: Vec<String> = vec![];
-
-    let mut is_rclone_server_started: bool = false;
-    for (_k, to_up) in &upload_list {
-        if cache::exists(cache.get(&to_up.file_or_dir_path).await.as_ref()).await {
-            let cache_last_up = cache::get_last_update_from_cache(
-                cache.get(&to_up.file_or_dir_path).await.as_ref(),
-            )
-            .await
-            .unwrap();
-
-            if cache::compare_last_update(cache_last_up, &to_up.file_or_dir_path).await? {
-                let path = PathBuf::from(&to_up.file_or_dir_path);
-                if sys_ops::is_dir(path.clone()).await? {
-                    let server = sync(
-                        parsed_toml,
-                        is_rclone_server_started,
-                        to_up,
-                        &mut mounted_remotes,
-                    )
-                    .await?;
-                    is_rclone_server_started = server.1;
-                    rclone_server = Some(server.0);
-                    let _ = cache::save_last_update_to_cache(&to_up.file_or_dir_path, &parsed_toml)
-                        .await?;
-                } else if sys_ops::is_file(path).await? {
-                    let server = file_sync(
-                        parsed_toml,
-                        is_rclone_server_started,
-                        to_up,
-                        &mut mounted_remotes,
-                    )
-                    .await?;
-                    is_rclone_server_started = server.1;
-                    rclone_server = Some(server.0);
-                    let _ = cache::save_last_update_to_cache(&to_up.file_or_dir_path, &parsed_toml)
-                        .await?;
-                } else {
-                    eprintln!(
-                        "Skipping: {} is neither a directory nor a file",
-                        to_up.file_or_dir_path
-                    );
-                    continue;
-                }
-            }
-            // new file to upload
-        } else {
-            let path = PathBuf::from(&to_up.file_or_dir_path);
-            if sys_ops::is_dir(path.clone()).await? {
-                let server = sync(
-                    parsed_toml,
-                    is_rclone_server_started,
-                    to_up,
-                    &mut mounted_remotes,
-                )
-                .await?;
-                is_rclone_server_started = server.1;
-                rclone_server = Some(server.0);
-                let _ =
-                    cache::save_last_update_to_cache(&to_up.file_or_dir_path, &parsed_toml).await?;
-            } else if sys_ops::is_file(path).await? {
-                let server = file_sync(
-                    parsed_toml,
-                    is_rclone_server_started,
-                    to_up,
-                    &mut mounted_remotes,
-                )
-                .await?;
-                is_rclone_server_started = server.1;
-                rclone_server = Some(server.0);
-                let _ =
-                    cache::save_last_update_to_cache(&to_up.file_or_dir_path, &parsed_toml).await?;
-            } else {
-                eprintln!(
-                    "Skipping: {} is neither a directory nor a file",
-                    to_up.file_or_dir_path
-                );
-                continue;
+    let sync_config = sync_config(parsed_toml).await?;
+    let session = match &sync_config.timeout {
+        Some(timeout) => {
+            SyncSession::with_timeout(toml::parse_duration_setting("sync", "timeout", timeout)?)
+        }
+        None => SyncSession::new(),
+    }
+    .with_bwlimit(sync_config.bwlimit);
+    let mut summary = SyncSummary::new();
+
+    let mut keys: Vec<&String> = upload_list.keys().collect();
+    keys.sort();
+    for key in keys {
+        let to_up = &upload_list[key];
+        if fail_fast && summary.has_failures() {
+            summary.record_not_attempted(key, to_up);
+            continue;
+        }
+        if session.timed_out() {
+            summary.record_error(key, to_up, ClSyncError::RunTimeout.into());
+            continue;
+        }
+        match sync_if_modified(parsed_toml, key, to_up, &session).await {
+            Ok(outcome) => summary.record(key, outcome),
+            Err(e) => {
+                eprintln!("Failed to sync [upload.{}]: {:?}", key, e);
+                summary.record_error(key, to_up, e);
             }
         }
     }
 
-    let _ = dismount_remotes(&mut mounted_remotes).await?;
-    // Stop rclone when done
-    if let Some(mut server) = rclone_server {
-        server.stop().await;
+    // Dismount and stop rclone when done
+    let finished = session.finish().await;
+    summary.print();
+    summary.into_result()?;
+    finished
+}
+
+// Sync an entry to the clouds that don't have its current local state yet.
+// A cloud failing shows up in the outcome, Err is for the entry as a whole.
+pub async fn sync_if_modified(
+    parsed_toml: &toml::TomlParser,
+    key: &str,
+    to_up: &toml::TomlUpload,
+    session: &SyncSession,
+) -> Result<EntryOutcome> {
+    let path = PathBuf::from(&to_up.file_or_dir_path);
+    if !sys_ops::is_dir(path.clone()).await? && !sys_ops::is_file(path.clone()).await? {
+        let reason = format!(
+            "{} is neither a directory nor a file",
+            to_up.file_or_dir_path
+        );
+        eprintln!("Skipping: {}", reason);
+        return Ok(to_up
+            .clouds()
+            .into_iter()
+            .map(|cloud| (cloud, CloudOutcome::Skipped(reason.to_string())))
+            .collect());
     }
-    Ok(())
+
+    let manifest_hash = sys_ops::manifest_hash(&path).await?;
+    let cache = cache::load(parsed_toml).await?;
+    let destinations = cache::destinations(parsed_toml, to_up).await;
+    let file = cache.get(key).await;
+    let clouds = cache::clouds_behind(file.as_ref(), to_up, &manifest_hash, &destinations).await?;
+
+    // new or modified file to upload
+    let mut results = if clouds.is_empty() {
+        vec![]
+    } else {
+        sync_entry(parsed_toml, key, to_up, &clouds, &manifest_hash, session).await?
+    };
+
+    let mut outcome = vec![];
+    for cloud in to_up.clouds() {
+        let cloud_outcome = match results.iter().position(|(synced, _)| *synced == cloud) {
+            Some(index) => match results.swap_remove(index).1 {
+                Ok(()) => CloudOutcome::Synced,
+                Err(e) => CloudOutcome::Failed(e),
+            },
+            None => CloudOutcome::UpToDate,
+        };
+        outcome.push((cloud.to_string(), cloud_outcome));
+    }
+    Ok(outcome)
 }
 
-async fn sync<'a>(
-    parsed_toml: &'a toml::TomlParser,
-    is_rclone_server_started: bool,
-    to_up: &'a toml::TomlUpload,
-    mounted_remotes: &'a mut Vec<String>,
-) -> Result<(RcloneServer, bool, &'a mut Vec<String>)> {
-    let rclone_server;
+// Collapse an entry's outcome for callers that only log it:
+// true when anything was uploaded, Err when any cloud failed
+pub fn entry_result(to_up: &toml::TomlUpload, outcome: EntryOutcome) -> Result<bool> {
+    let mut synced = false;
+    let mut failures = vec![];
+    for (cloud, cloud_outcome) in outcome {
+        match cloud_outcome {
+            CloudOutcome::Synced => synced = true,
+            CloudOutcome::Failed(e) => failures.push((cloud, e)),
+            CloudOutcome::UpToDate | CloudOutcome::Skipped(_) => {}
+        }
+    }
+    if !failures.is_empty() {
+        return Err(ClSyncError::Sync {
+            path: to_up.file_or_dir_path.to_string(),
+            failures,
+        }
+        .into());
+    }
+    Ok(synced)
+}
 
-    if !is_rclone_server_started {
-        rclone_server = RcloneServer::start().await;
+// Upload a single entry to the given clouds and record each cloud's outcome in the cache.
+pub async fn sync_entry(
+    parsed_toml: &toml::TomlParser,
+    key: &str,
+    to_up: &toml::TomlUpload,
+    clouds: &[String],
+    manifest_hash: &str,
+    session: &SyncSession,
+) -> Result<CloudResults> {
+    let started_at = Local::now();
+    let deadline = session.entry_deadline(key, to_up)?;
+    let mut archive_hashes = HashMap::new();
+    let results = if !sys_ops::is_dir(PathBuf::from(&to_up.file_or_dir_path)).await? {
+        file_sync(parsed_toml, session, key, to_up, clouds, deadline).await?
+    } else if to_up.archive.is_some() {
+        let hashes = &mut archive_hashes;
+        archive_sync(parsed_toml, session, key, to_up, clouds, deadline, hashes).await?
     } else {
-        //debug!("server all ready started.")
-        println!("server all ready started.")
+        sync(parsed_toml, session, key, to_up, clouds, deadline).await?
+    };
+
+    cache::save_cloud_results_to_cache(
+        key,
+        to_up,
+        manifest_hash,
+        &archive_hashes,
+        started_at,
+        &results,
+        parsed_toml,
+    )
+    .await?;
+    Ok(results)
+}
+
+// Outcome of a finished rclone job, None when it never finished
+fn job_result(
+    job_id: u64,
+    status: Option<&rclone::JobStatus>,
+) -> std::result::Result<(), ClSyncError> {
+    match status {
+        Some(status) if status.success => Ok(()),
+        Some(status) => Err(ClSyncError::Job {
+            job_id,
+            error: status
+                .error
+                .clone()
+                .unwrap_or_else(|| RcloneError::Other("rclone job failed".to_string())),
+        }),
+        None => Err(ClSyncError::Job {
+            job_id,
+            error: RcloneError::Other("rclone job did not finish".to_string()),
+        }),
     }
+}
 
+// Mount every cloud of the entry. Clouds that can't be mounted get their
+// error in results and are left out of the returned list.
+async fn mount_clouds(
+    parsed_toml: &toml::TomlParser,
+    session: &SyncSession,
+    clouds: &[String],
+    deadline: Option<Instant>,
+    results: &mut CloudResults,
+) -> Result<Vec<toml::CloudProviders>> {
     let remote_list = match parsed_toml
         .get_section_from_toml(toml::TomlSection::CloudProviders)
         .await
     {
         Ok(toml::TomlToParse::CloudProviders(cloud)) => cloud,
-        _ => return Err(anyhow::anyhow!("Unexpected section type for upload list")),
+        Ok(_) => return Err(anyhow!("Unexpected section type for cloud providers")),
+        Err(e) => return Err(e),
     };
 
-    while !rclone::RcloneServer::is_running().await {
-        println!("Waiting for rclone to start...");
-    }
-
-    // mount for this upload
-    let mut mount_jobid: Vec<u16> = vec![];
-    for remote in &to_up.upload_to_clouds {
-        let remote_path = remote_list.get(remote).unwrap();
-        mounted_remotes.push(remote_path.dir.to_string());
-        let mount = rclone::mount_remote(&remote_path).await?;
-        mount_jobid.push(mount.job_id.unwrap());
+    let mut mounted = vec![];
+    let mut mount_jobid: Vec<u64> = vec![];
+    for remote in clouds {
+        let Some(remote_path) = remote_list.get(remote) else {
+            let error = TomlError::UnknownCloud(remote.to_string());
+            results.push((remote.to_string(), Err(error.into())));
+            continue;
+        };
+        // mounted for an earlier entry of this run or one running alongside
+        let _mounting = session.lock_mount_point(&remote_path.dir).await;
+        if session.is_mounted(&remote_path.dir).await {
+            mounted.push(remote_path.clone());
+            continue;
+        }
+        match rclone::mount_remote(&session.client, remote_path).await {
+            Ok(job_id) => {
+                session.add_mounted_remote(&remote_path.dir).await;
+                mount_jobid.push(job_id);
+                mounted.push(remote_path.clone());
+            }
+            Err(e) => {
+                let error = ClSyncError::Mount {
+                    remote: remote.to_string(),
+                    mount_point: remote_path.dir.to_string(),
+                    reason: e.to_string(),
+                };
+                results.push((remote.to_string(), Err(error)));
+            }
+        }
     }
-    let _ = job_progress(&mut mount_jobid).await;
-
-    let mut mount_jobid: Vec<u16> = vec![];
-    for remote in &to_up.upload_to_clouds {
-        let remote_path = format!("{}:{}", remote, to_up.upload_to_cloud_dir);
-        let sync =
-            rclone::sync_sync(to_up.file_or_dir_path.clone(), remote_path.to_string()).await?;
-        mount_jobid.push(sync.job_id.unwrap());
+    if wait_for_jobs(session, &mount_jobid, deadline)
+        .await
+        .is_none()
+    {
+        eprintln!("Timed out waiting for the mounts, uploading anyway");
     }
-    let _ = job_progress(&mut mount_jobid).await;
+    Ok(mounted)
+}
 
-    // Stop rclone when done
-    Ok((rclone_server, true, mounted_remotes))
+// Final status of every job, None when the deadline passed first
+async fn wait_for_jobs(
+    session: &SyncSession,
+    jobs: &[u64],
+    deadline: Option<Instant>,
+) -> Option<HashMap<u64, rclone::JobStatus>> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, session.jobs.wait_all(jobs))
+            .await
+            .ok(),
+        None => Some(session.jobs.wait_all(jobs).await),
+    }
 }
 
-async fn file_sync<'a>(
-    parsed_toml: &'a toml::TomlParser,
-    is_rclone_server_started: bool,
-    to_up: &'a toml::TomlUpload,
-    mounted_remotes: &'a mut Vec<String>,
-) -> Result<(RcloneServer, bool, &'a mut Vec<String>)> {
-    let rclone_server;
+// Submit a job and wait for it to finish, a job still running
+// at the deadline is stopped and reported as timed out
+async fn run_job(
+    session: &SyncSession,
+    submit: impl std::future::Future<Output = std::result::Result<u64, RcError>>,
+    deadline: Option<Instant>,
+) -> std::result::Result<(), ClSyncError> {
+    let job_id = submit.await?;
+    let Some(finished) = wait_for_jobs(session, &[job_id], deadline).await else {
+        println!("rclone job {} timed out, stopping it", job_id);
+        if let Err(e) = rclone::stop_job(&session.client, job_id).await {
+            eprintln!("Failed to stop rclone job {}: {}", job_id, e);
+        }
+        return Err(ClSyncError::JobTimeout { job_id });
+    };
+    job_result(job_id, finished.get(&job_id))
+}
 
-    if !is_rclone_server_started {
-        rclone_server = RcloneServer::start().await;
-    } else {
-        //debug!("server all ready started.")
-        println!("server all ready started.");
+// Options of the upload job to one cloud: tagged with the key of the
+// entry, with the filters of its target, the rclone_options of the entry
+// and the cloud and the per-file bandwidth limit of the target or else the cloud
+fn job_options(
+    key: &str,
+    to_up: &toml::TomlUpload,
+    provider: &toml::CloudProviders,
+) -> rc::JobOptions {
+    let target = to_up.target(&provider.cloud_name);
+    let mut job = rc::JobOptions::background(Some(key));
+    if !target.filters.is_empty() {
+        let mut filter = serde_json::Map::new();
+        filter.insert("FilterRule".to_string(), serde_json::json!(target.filters));
+        job.filter = Some(filter);
     }
-
-    let remote_list = match parsed_toml
-        .get_section_from_toml(toml::TomlSection::CloudProviders)
-        .await
+    let mut config = rclone_options::merged(to_up, provider);
+    if let Some(bwlimit) = target
+        .bwlimit_file
+        .as_ref()
+        .or(provider.bwlimit_file.as_ref())
     {
-        Ok(toml::TomlToParse::CloudProviders(cloud)) => cloud,
-        _ => return Err(anyhow::anyhow!("Unexpected section type for upload list")),
+        config.insert("BwLimitFile".to_string(), serde_json::json!(bwlimit));
+    }
+    if !config.is_empty() {
+        job.config = Some(config);
+    }
+    job
+}
+
+// Upload a dir packed into one archive with operations/copyfile. Clouds
+// with the same filters share an archive, a cloud that already has an
+// identical one is skipped. The hash of the archive of every cloud that
+// got it goes to archive_hashes.
+async fn archive_sync(
+    parsed_toml: &toml::TomlParser,
+    session: &SyncSession,
+    key: &str,
+    to_up: &toml::TomlUpload,
+    clouds: &[String],
+    deadline: Option<Instant>,
+    archive_hashes: &mut HashMap<String, String>,
+) -> Result<CloudResults> {
+    let Some(settings) = &to_up.archive else {
+        return sync(parsed_toml, session, key, to_up, clouds, deadline).await;
     };
+    session.start_rclone_server().await?;
 
-    while !rclone::RcloneServer::is_running().await {
-        println!("Waiting for rclone to start...");
-    }
+    let mut results: CloudResults = vec![];
+    rclone_options::check(parsed_toml, session, key, to_up, clouds).await?;
+    let clouds =
+        quota::preflight(parsed_toml, &session.client, to_up, clouds, &mut results).await?;
 
     // mount for this upload
-    let mut mount_jobid: Vec<u16> = vec![];
-    for remote in &to_up.upload_to_clouds {
-        let remote_path = remote_list.get(remote).unwrap();
-        mounted_remotes.push(remote_path.dir.to_string());
-        let mount = rclone::mount_remote(&remote_path).await?;
-        mount_jobid.push(mount.job_id.unwrap());
+    let mounted = mount_clouds(parsed_toml, session, &clouds, deadline, &mut results).await?;
+
+    let name = settings.file_name(to_up);
+    let mut archives: HashMap<Vec<String>, archive::Archive> = HashMap::new();
+    for remote in &mounted {
+        let filters = to_up.target(&remote.cloud_name).filters;
+        if !archives.contains_key(&filters) {
+            let path = Path::new(&to_up.file_or_dir_path);
+            let built = archive::build(key, path, &filters, &name, settings.level).await?;
+            archives.insert(filters, built);
+        }
     }
-    let _ = job_progress(&mut mount_jobid).await;
-
-    let mut mount_jobid: Vec<u16> = vec![];
-    for remote in &to_up.upload_to_clouds {
-        let local_dir = Path::new(&to_up.upload_to_cloud_dir);
-        let colon_remote = format!("{}:", remote);
-        let remote_dst_path = format!("{}:{}", remote, &to_up.upload_to_cloud_dir);
-
-        let sync = rclone::copyfile(
-            local_dir.parent().unwrap().to_string_lossy().to_string(),
-            to_up.file_or_dir_name.to_string(),
-            colon_remote,
-            remote_dst_path,
-        )
-        .await?;
-        mount_jobid.push(sync.job_id.unwrap());
+    let cached = cache::load(parsed_toml).await?.get(key).await;
+
+    let snapshot = snapshot::name(Local::now());
+    let uploaded = upload_to_clouds(key, to_up, &mounted, deadline, |remote| {
+        let cloud = remote.cloud_name.to_string();
+        let archive = &archives[&to_up.target(&cloud).filters];
+        let destination = snapshot::destination(to_up, remote, &snapshot);
+        let hash_matches = cached
+            .as_ref()
+            .and_then(|file| file.clouds.get(&cloud))
+            .and_then(|state| state.archive_hash.as_ref())
+            .is_some_and(|hash| *hash == archive.hash);
+        let dst_file = destination.file(&archive.name);
+        // the filters went into the archive, they'd only filter the archive itself
+        let mut job = job_options(key, to_up, remote);
+        job.filter = None;
+        let upload = run_job(
+            session,
+            rclone::transfer_file(
+                &session.client,
+                toml::UploadMode::Copy,
+                archive.src_fs(),
+                archive.name.to_string(),
+                destination.fs.to_string(),
+                dst_file.to_string(),
+                job,
+            ),
+            deadline,
+        );
+        let base = toml::Destination::resolve(to_up, remote);
+        async move {
+            // the same archive was uploaded and is still where it goes now,
+            // a new snapshot dir or a changed dir doesn't have it
+            if hash_matches
+                && archive
+                    .is_on_cloud(&session.client, &destination.fs, &dst_file)
+                    .await
+            {
+                println!(
+                    "{} on {} is already up to date, skipping the upload",
+                    archive.name, cloud
+                );
+                return Ok(());
+            }
+            upload.await?;
+            prune_snapshots(session, to_up, &cloud, &base).await;
+            Ok(())
+        }
+    })
+    .await?;
+    discard_failed_snapshots(session, to_up, &mounted, &snapshot, &uploaded).await;
+
+    for (cloud, result) in &uploaded {
+        if result.is_ok() {
+            let hash = &archives[&to_up.target(cloud).filters].hash;
+            archive_hashes.insert(cloud.to_string(), hash.to_string());
+        }
     }
-    let _ = job_progress(&mut mount_jobid).await;
+    results.extend(uploaded);
 
-    // Stop rclone when done
-    Ok((rclone_server, true, mounted_remotes))
+    Ok(results)
 }
 
-pub async fn job_progress(mount_jobid: &mut Vec<u16>) -> Result<()> {
-    while !mount_jobid.is_empty() {
-        mount_jobid.retain_mut(|job_id| {
-            let status = tokio::task::block_in_place(|| {
-                tokio::runtime::Handle::current().block_on(rclone::check_job_status(*job_id))
-            });
-
-            match status {
-                Ok(false) => true, // Keep the job if it's not completed
-                Ok(true) => {
-                    debug!("job_id {:?}", job_id);
-                    false // Remove completed job
-                }
-                Err(e) => {
-                    debug!("Error checking job status: {:?}", e);
-                    true // Keep the job to retry
-                }
-            }
+// Purge the snapshots in base, the destination of the entry on the cloud,
+// its retention doesn't keep after a snapshot was uploaded there.
+// Failing leaves them for the next upload.
+async fn prune_snapshots(
+    session: &SyncSession,
+    to_up: &toml::TomlUpload,
+    cloud: &str,
+    base: &toml::Destination,
+) {
+    if to_up.mode(cloud) != toml::UploadMode::Snapshot {
+        return;
+    }
+    if let Err(e) = snapshot::prune(&session.client, base, &to_up.retention).await {
+        eprintln!(
+            "Failed to purge old snapshots of {} on {}: {}",
+            to_up.file_or_dir_name, cloud, e
+        );
+    }
+}
+
+// Remove the snapshot dir of every cloud the upload failed on after its retries
+async fn discard_failed_snapshots(
+    session: &SyncSession,
+    to_up: &toml::TomlUpload,
+    mounted: &[toml::CloudProviders],
+    snapshot: &str,
+    uploaded: &CloudResults,
+) {
+    for remote in mounted {
+        let failed = uploaded
+            .iter()
+            .any(|(cloud, result)| *cloud == remote.cloud_name && result.is_err());
+        if !failed || to_up.mode(&remote.cloud_name) != toml::UploadMode::Snapshot {
+            continue;
+        }
+        let destination = snapshot::destination(to_up, remote, snapshot);
+        if let Err(e) = snapshot::discard(&session.client, &destination).await {
+            eprintln!(
+                "Failed to remove the partial snapshot {}: {}",
+                destination.remote(),
+                e
+            );
+        }
+    }
+}
+
+// Upload to every mounted cloud at the same time, each with its own retries
+async fn upload_to_clouds<F, Fut>(
+    key: &str,
+    to_up: &toml::TomlUpload,
+    mounted: &[toml::CloudProviders],
+    deadline: Option<Instant>,
+    upload: F,
+) -> Result<CloudResults>
+where
+    F: Fn(&toml::CloudProviders) -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<(), ClSyncError>>,
+{
+    let mut uploads = vec![];
+    for remote in mounted {
+        let policy = retry::RetryPolicy::for_cloud(key, to_up, remote)?;
+        let label = format!("Uploading [upload.{}] to {}", key, remote.cloud_name);
+        let upload = &upload;
+        uploads.push(async move {
+            let result = retry::with_retries(&label, policy, deadline, || upload(remote)).await;
+            (remote.cloud_name.to_string(), result)
         });
+    }
+    Ok(futures::future::join_all(uploads).await)
+}
+
+async fn sync(
+    parsed_toml: &toml::TomlParser,
+    session: &SyncSession,
+    key: &str,
+    to_up: &toml::TomlUpload,
+    clouds: &[String],
+    deadline: Option<Instant>,
+) -> Result<CloudResults> {
+    session.start_rclone_server().await?;
+
+    let mut results: CloudResults = vec![];
+    rclone_options::check(parsed_toml, session, key, to_up, clouds).await?;
+    let clouds =
+        quota::preflight(parsed_toml, &session.client, to_up, clouds, &mut results).await?;
 
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    // mount for this upload
+    let mounted = mount_clouds(parsed_toml, session, &clouds, deadline, &mut results).await?;
+
+    let snapshot = snapshot::name(Local::now());
+    let uploaded = upload_to_clouds(key, to_up, &mounted, deadline, |remote| {
+        let destination = snapshot::destination(to_up, remote, &snapshot);
+        let upload = run_job(
+            session,
+            rclone::sync_dir(
+                &session.client,
+                to_up.mode(&remote.cloud_name),
+                to_up.file_or_dir_path.clone(),
+                destination.remote(),
+                job_options(key, to_up, remote),
+            ),
+            deadline,
+        );
+        let cloud = remote.cloud_name.to_string();
+        let base = toml::Destination::resolve(to_up, remote);
+        async move {
+            upload.await?;
+            prune_snapshots(session, to_up, &cloud, &base).await;
+            Ok(())
+        }
+    })
+    .await?;
+    discard_failed_snapshots(session, to_up, &mounted, &snapshot, &uploaded).await;
+    results.extend(uploaded);
+
+    Ok(results)
+}
+
+async fn file_sync(
+    parsed_toml: &toml::TomlParser,
+    session: &SyncSession,
+    key: &str,
+    to_up: &toml::TomlUpload,
+    clouds: &[String],
+    deadline: Option<Instant>,
+) -> Result<CloudResults> {
+    session.start_rclone_server().await?;
+
+    let mut results: CloudResults = vec![];
+    rclone_options::check(parsed_toml, session, key, to_up, clouds).await?;
+    let clouds =
+        quota::preflight(parsed_toml, &session.client, to_up, clouds, &mut results).await?;
+
+    // mount for this upload
+    let mounted = mount_clouds(parsed_toml, session, &clouds, deadline, &mut results).await?;
+
+    let snapshot = snapshot::name(Local::now());
+    let mut copies = HashMap::new();
+    for remote in &mounted {
+        let copy = file_upload::FileCopy::new(key, to_up, remote, &snapshot)?;
+        copies.insert(remote.cloud_name.to_string(), copy);
     }
-    Ok(())
+    let uploaded = upload_to_clouds(key, to_up, &mounted, deadline, |remote| {
+        let copy = &copies[&remote.cloud_name];
+        let upload =
+            file_upload::upload_file(session, copy, job_options(key, to_up, remote), deadline);
+        let cloud = remote.cloud_name.to_string();
+        let base = toml::Destination::resolve(to_up, remote);
+        async move {
+            upload.await?;
+            prune_snapshots(session, to_up, &cloud, &base).await;
+            Ok(())
+        }
+    })
+    .await?;
+    discard_failed_snapshots(session, to_up, &mounted, &snapshot, &uploaded).await;
+    results.extend(uploaded);
+
+    Ok(results)
 }
 
-async fn dismount_remotes(mounted_remotes: &mut Vec<String>) -> Result<()> {
-    //println!("{:?}", mounted_remotes);
-    for remote in mounted_remotes {
-        sys_ops::fusermount(remote).await?;
+#[cfg(test)]
+mod cl_sync_test {
+    use super::*;
+    use crate::test_fixtures::{entry, parser_with_clouds, provider, RcStub};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_mount_once_for_entries_running_together() -> Result<()> {
+        let stub = RcStub::start(|command, params| match command {
+            "mount/mount" => json!({ "jobid": 1 }),
+            "job/list" => json!({ "jobids": [1], "runningIds": [] }),
+            "job/status" => json!({ "id": params["jobid"], "finished": true, "success": true }),
+            _ => json!({ "error": "unexpected call", "status": 500 }),
+        })
+        .await;
+        let session = stub.session();
+        let tmp = tempfile::tempdir()?;
+        let parsed_toml = parser_with_clouds(
+            &[("vault", entry("/home/user/vault", &["dge"]))],
+            &[provider("dge")],
+            &tmp.path().join("cache"),
+        );
+        let clouds = vec!["dge".to_string()];
+
+        let mount = || async {
+            let mut results = vec![];
+            mount_clouds(&parsed_toml, &session, &clouds, None, &mut results).await
+        };
+        let (first, second) = tokio::join!(mount(), mount());
+        assert_eq!(first?.len(), 1);
+        assert_eq!(second?.len(), 1);
+        assert_eq!(stub.params("mount/mount").len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_job_options() {
+        let target = toml::TargetSettings {
+            cloud: "dge".to_string(),
+            filters: vec!["- *.tmp".to_string()],
+            bwlimit_file: Some("1M".to_string()),
+            ..Default::default()
+        };
+        let mut to_up = toml::TomlUpload {
+            upload_to_clouds: vec![toml::UploadTarget::Table(target)],
+            ..Default::default()
+        };
+        to_up
+            .rclone_options
+            .insert("Transfers".to_string(), json!(8));
+        let mut provider = toml::CloudProviders {
+            cloud_name: "dge".to_string(),
+            bwlimit_file: Some("08:00,512k 23:00,off".to_string()),
+            ..Default::default()
+        };
+        provider
+            .rclone_options
+            .insert("Transfers".to_string(), json!(4));
+        provider
+            .rclone_options
+            .insert("UseListR".to_string(), json!(true));
+
+        let job = serde_json::to_value(job_options("vault", &to_up, &provider)).unwrap();
+        assert_eq!(
+            job,
+            json!({
+                "_async": true,
+                "_group": "vault",
+                "_filter": { "FilterRule": ["- *.tmp"] },
+                "_config": { "Transfers": 8, "UseListR": true, "BwLimitFile": "1M" },
+            })
+        );
+
+        // listed by name, only the settings of the cloud apply
+        to_up.upload_to_clouds = vec![toml::UploadTarget::from("dge")];
+        to_up.rclone_options.clear();
+        let job = serde_json::to_value(job_options("vault", &to_up, &provider)).unwrap();
+        assert!(job.get("_filter").is_none());
+        assert_eq!(job["_config"]["BwLimitFile"], "08:00,512k 23:00,off");
+        assert_eq!(job["_config"]["Transfers"], 4);
+
+        let job = serde_json::to_value(job_options(
+            "vault",
+            &to_up,
+            &toml::CloudProviders::default(),
+        ))
+        .unwrap();
+        assert!(job.get("_config").is_none());
     }
-    Ok(())
 }
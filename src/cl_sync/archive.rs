@@ -0,0 +1,338 @@
+use anyhow::Result;
+use globset::{GlobBuilder, GlobMatcher};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tracing::debug;
+
+use crate::error::{ClSyncError, TomlError};
+use crate::operations::rc::RcClient;
+use crate::operations::rclone;
+
+// A tarball of an entry in a temp dir, deleted when it is dropped
+pub struct Archive {
+    pub dir: TempDir,
+    pub name: String,
+    pub hash: String,
+    pub size: u64,
+}
+
+impl Archive {
+    // The temp dir as the srcFs of operations/copyfile
+    pub fn src_fs(&self) -> String {
+        self.dir.path().to_string_lossy().to_string()
+    }
+
+    // The cloud has a file of this size at fs/file. Together with the hash
+    // cached for the cloud that is the archive uploaded there before,
+    // failing to find out counts as not there.
+    pub async fn is_on_cloud(&self, client: &RcClient, fs: &str, file: &str) -> bool {
+        match rclone::stat(client, fs, file).await {
+            Ok(Some(item)) => !item.is_dir && item.size == self.size as i64,
+            Ok(None) => false,
+            Err(e) => {
+                debug!("Failed to find {}{}: {:?}", fs, file, e);
+                false
+            }
+        }
+    }
+}
+
+// The rclone filter rules of a target, "- *.tmp" or "+ docs/**".
+// The first rule that matches decides, paths no rule matches are included.
+// Patterns starting with / match from the root of the entry, others
+// at any depth, patterns ending with / only match dirs. Like in rclone
+// * stays inside one dir and ** crosses dirs.
+pub struct FilterRules {
+    rules: Vec<(bool, bool, GlobMatcher)>,
+}
+
+impl FilterRules {
+    pub fn parse(filters: &[String]) -> std::result::Result<Self, String> {
+        let mut rules = vec![];
+        for filter in filters {
+            let (include, pattern) = match filter.split_once(' ') {
+                Some(("+", pattern)) => (true, pattern.trim()),
+                Some(("-", pattern)) => (false, pattern.trim()),
+                _ => return Err(format!("{:?} is not a \"+ \" or \"- \" rule", filter)),
+            };
+            let dirs_only = pattern.ends_with('/');
+            let glob = match pattern.strip_prefix('/') {
+                Some(anchored) => anchored.to_string(),
+                None if pattern.starts_with("**") => pattern.to_string(),
+                None => format!("**/{}", pattern),
+            };
+            let matcher = GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| format!("{:?}: {}", filter, e))?
+                .compile_matcher();
+            rules.push((include, dirs_only, matcher));
+        }
+        Ok(Self { rules })
+    }
+
+    // path is relative to the root of the entry, an excluded dir
+    // is left out with everything in it
+    fn includes(&self, path: &str, is_dir: bool) -> bool {
+        let path = if is_dir {
+            format!("{}/", path)
+        } else {
+            path.to_string()
+        };
+        self.rules
+            .iter()
+            .filter(|(_, dirs_only, _)| is_dir || !dirs_only)
+            .find(|(_, _, matcher)| matcher.is_match(&path))
+            .is_none_or(|(include, _, _)| *include)
+    }
+}
+
+// Pack the dir at path into a tar.zst in a new temp dir. The archive
+// holds the dir itself with its local name. Times, owners and permissions
+// are left out, so the same files always give the same hash.
+pub async fn build(
+    key: &str,
+    path: &Path,
+    filters: &[String],
+    name: &str,
+    level: i32,
+) -> Result<Archive> {
+    let rules = FilterRules::parse(filters).map_err(|reason| TomlError::InvalidSetting {
+        section: format!("upload.{}", key),
+        setting: "filters",
+        reason,
+    })?;
+    let path = path.to_path_buf();
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || {
+        let dir = tempfile::Builder::new()
+            .prefix("cl_sync_archive")
+            .tempdir()
+            .map_err(|source| ClSyncError::Filesystem {
+                path: std::env::temp_dir(),
+                source,
+            })?;
+        let (hash, size) = write_archive(&path, &rules, &dir.path().join(&name), level)?;
+        Ok(Archive {
+            dir,
+            name,
+            hash,
+            size,
+        })
+    })
+    .await?
+}
+
+fn write_archive(
+    root: &Path,
+    rules: &FilterRules,
+    archive_path: &Path,
+    level: i32,
+) -> std::result::Result<(String, u64), ClSyncError> {
+    let archive_error = |source| ClSyncError::Filesystem {
+        path: archive_path.to_path_buf(),
+        source,
+    };
+    let file = std::fs::File::create(archive_path).map_err(archive_error)?;
+    let hashing = HashingWriter {
+        inner: file,
+        hasher: Sha256::new(),
+        written: 0,
+    };
+    let encoder = zstd::Encoder::new(hashing, level).map_err(archive_error)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.mode(tar::HeaderMode::Deterministic);
+    builder.follow_symlinks(false);
+
+    let top = root
+        .file_name()
+        .map_or_else(|| PathBuf::from("."), PathBuf::from);
+    builder
+        .append_dir(&top, root)
+        .map_err(|source| fs_error(root, source))?;
+    append_dir(&mut builder, root, root, &top, rules)?;
+
+    let encoder = builder.into_inner().map_err(archive_error)?;
+    let hashing = encoder.finish().map_err(archive_error)?;
+    hashing.inner.sync_all().map_err(archive_error)?;
+    Ok((format!("{:x}", hashing.hasher.finalize()), hashing.written))
+}
+
+fn append_dir<W: Write>(
+    builder: &mut tar::Builder<W>,
+    root: &Path,
+    dir: &Path,
+    top: &Path,
+    rules: &FilterRules,
+) -> std::result::Result<(), ClSyncError> {
+    let mut entries = std::fs::read_dir(dir)
+        .map_err(|source| fs_error(dir, source))?
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|source| fs_error(dir, source))?;
+    // sorted so the archive doesn't depend on the order of the file system
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .map_err(|source| fs_error(&path, source))?;
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if !rules.includes(&relative.to_string_lossy(), file_type.is_dir()) {
+            continue;
+        }
+        let name = top.join(relative);
+        if file_type.is_dir() {
+            builder
+                .append_dir(&name, &path)
+                .map_err(|source| fs_error(&path, source))?;
+            append_dir(builder, root, &path, top, rules)?;
+        } else {
+            builder
+                .append_path_with_name(&path, &name)
+                .map_err(|source| fs_error(&path, source))?;
+        }
+    }
+    Ok(())
+}
+
+fn fs_error(path: &Path, source: std::io::Error) -> ClSyncError {
+    ClSyncError::Filesystem {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+// Hashes and counts what is written to the archive file, so it isn't read twice
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod archive_test {
+    use super::*;
+    use crate::test_fixtures::RcStub;
+    use serde_json::json;
+
+    fn rules(filters: &[&str]) -> FilterRules {
+        let filters: Vec<String> = filters.iter().map(|filter| filter.to_string()).collect();
+        FilterRules::parse(&filters).unwrap()
+    }
+
+    #[test]
+    fn test_filter_rules() {
+        let tmp = rules(&["- *.tmp", "- /cache/", "+ **"]);
+        assert!(tmp.includes("notes.md", false));
+        assert!(!tmp.includes("notes.tmp", false));
+        assert!(!tmp.includes("sub/dir/notes.tmp", false));
+        assert!(!tmp.includes("cache", true));
+        assert!(tmp.includes("sub/cache", true));
+        // dir rules don't match files
+        assert!(tmp.includes("cache", false));
+
+        let only_docs = rules(&["+ /docs/**", "- **"]);
+        assert!(only_docs.includes("docs/a.md", false));
+        assert!(only_docs.includes("docs/sub/x.md", false));
+        assert!(!only_docs.includes("src/main.rs", false));
+
+        // * doesn't cross dirs
+        let top_md = rules(&["- /docs/*.md"]);
+        assert!(!top_md.includes("docs/a.md", false));
+        assert!(top_md.includes("docs/sub/x.md", false));
+        let any_md = rules(&["- *.md"]);
+        assert!(!any_md.includes("docs/sub/x.md", false));
+        let in_sub = rules(&["- /docs/*/x.md"]);
+        assert!(!in_sub.includes("docs/sub/x.md", false));
+        assert!(in_sub.includes("docs/sub/deeper/x.md", false));
+
+        assert!(FilterRules::parse(&["*.tmp".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_archive() -> Result<()> {
+        let root =
+            std::env::temp_dir().join(format!("cl_sync_archive_test_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub"))?;
+        std::fs::write(root.join("a.md"), "a")?;
+        std::fs::write(root.join("sub/b.md"), "b")?;
+        std::fs::write(root.join("sub/c.tmp"), "c")?;
+        let filters = vec!["- *.tmp".to_string()];
+
+        let archive = build("vault", &root, &filters, "vault.tar.zst", 3).await?;
+        let file = std::fs::File::open(archive.dir.path().join(&archive.name))?;
+        assert_eq!(file.metadata()?.len(), archive.size);
+        let mut tarball = tar::Archive::new(zstd::Decoder::new(file)?);
+        let mut names = vec![];
+        for entry in tarball.entries()? {
+            names.push(entry?.path()?.to_string_lossy().to_string());
+        }
+        let top = root.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(
+            names,
+            vec![
+                top.to_string(),
+                format!("{}/a.md", top),
+                format!("{}/sub", top),
+                format!("{}/sub/b.md", top),
+            ]
+        );
+
+        // a file written again with the same content, or an excluded
+        // file changing, gives the same archive
+        std::fs::write(root.join("a.md"), "a")?;
+        std::fs::write(root.join("sub/c.tmp"), "changed")?;
+        let again = build("vault", &root, &filters, "vault.tar.zst", 3).await?;
+        assert_eq!(archive.hash, again.hash);
+
+        std::fs::write(root.join("a.md"), "changed")?;
+        let changed = build("vault", &root, &filters, "vault.tar.zst", 3).await?;
+        assert_ne!(archive.hash, changed.hash);
+
+        // only where the cloud has a file of its size
+        let size = archive.size;
+        let stub = RcStub::start(move |_, params| match params["remote"].as_str() {
+            Some("Vault/vault.tar.zst") => {
+                json!({ "item": { "Name": "vault.tar.zst", "Size": size } })
+            }
+            Some("Vault/2026-10-19_031500/vault.tar.zst") => json!({ "item": null }),
+            _ => json!({ "item": { "Name": "vault.tar.zst", "Size": size + 1 } }),
+        })
+        .await;
+        let client = stub.client();
+        assert!(
+            archive
+                .is_on_cloud(&client, "dge:", "Vault/vault.tar.zst")
+                .await
+        );
+        assert!(
+            !archive
+                .is_on_cloud(&client, "dge:", "Vault/2026-10-19_031500/vault.tar.zst")
+                .await
+        );
+        assert!(
+            !archive
+                .is_on_cloud(&client, "dge:", "Old/vault.tar.zst")
+                .await
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
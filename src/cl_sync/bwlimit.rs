@@ -0,0 +1,20 @@
+use anyhow::Result;
+
+use crate::error::DaemonError;
+use crate::operations::rc::RcClient;
+use crate::operations::rclone::{self, RcloneServer, RC_PORT};
+
+// Show or change the limit of the rclone daemon of a sync, watch or daemon
+// run that is active right now. The change lasts until that run ends,
+// [sync] bwlimit or --bwlimit apply again to the next one.
+pub async fn change_bwlimit(rate: Option<&str>) -> Result<()> {
+    if !RcloneServer::is_running().await {
+        return Err(DaemonError::NotRunning { port: RC_PORT }.into());
+    }
+    let current = rclone::bwlimit(&RcClient::default(), rate).await?;
+    match rate {
+        Some(_) => println!("Bandwidth limit changed to {}", current),
+        None => println!("Bandwidth limit: {}", current),
+    }
+    Ok(())
+}
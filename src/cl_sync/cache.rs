@@ -1,8 +1,15 @@
+use crate::cl_sync::CloudResults;
 use crate::operations::{cl_sync_cache, sys_ops, toml};
 
 use anyhow::Result;
 use chrono::{DateTime, Local};
+use hashbrown::HashMap;
 use tokio::fs;
+use tokio::sync::Mutex;
+
+// Held while the cache file is loaded, changed and saved again
+// so entries synced at the same time don't overwrite each other
+static CACHE_WRITE_LOCK: Mutex<()> = Mutex::const_new(());
 
 pub async fn load(parsed_toml: &toml::TomlParser) -> Result<cl_sync_cache::ClCache> {
     cl_sync_cache::ClCache::new(parsed_toml).await
@@ -22,15 +29,15 @@ pub async fn get_last_update_from_cache(
     if let Some(structure) = file {
         return Ok(structure.last_saved);
     }
-    return Err(anyhow::anyhow!("Failed to read cache last saved"));
+    Err(anyhow::anyhow!("Failed to read cache last saved"))
 }
 
-pub async fn compare_last_update(cache_time: DateTime<Local>, file_time: &String) -> Result<bool> {
-    match fs::metadata(file_time.as_str()).await {
+pub async fn compare_last_update(cache_time: DateTime<Local>, file_time: &str) -> Result<bool> {
+    match fs::metadata(file_time).await {
         Ok(data) => {
             if let Ok(modified) = data.modified() {
                 let dt_file = sys_ops::to_epoch(modified.into()).await;
-                let dt_cache = sys_ops::to_epoch(cache_time.into()).await;
+                let dt_cache = sys_ops::to_epoch(cache_time).await;
 
                 if dt_file > dt_cache {
                     return Ok(true);
@@ -46,21 +53,216 @@ pub async fn compare_last_update(cache_time: DateTime<Local>, file_time: &String
     Ok(false)
 }
 
-pub async fn save_last_update_to_cache(
-    file_or_dir_path: &str,
+// Where each cloud of the entry uploads it, the fs and path without the
+// backend options of the remote. Clouds without a provider are left out.
+pub async fn destinations(
+    parsed_toml: &toml::TomlParser,
+    to_up: &toml::TomlUpload,
+) -> HashMap<String, String> {
+    let providers = match parsed_toml
+        .get_section_from_toml(toml::TomlSection::CloudProviders)
+        .await
+    {
+        Ok(toml::TomlToParse::CloudProviders(providers)) => providers,
+        _ => return HashMap::new(),
+    };
+    to_up
+        .clouds()
+        .into_iter()
+        .filter_map(|cloud| {
+            let provider = providers.get(&cloud)?;
+            let destination = toml::Destination::resolve(to_up, provider);
+            let remote = format!("{}:{}", provider.remote_name(), destination.path);
+            Some((cloud, remote))
+        })
+        .collect()
+}
+
+// Clouds of an entry that don't have its current local state yet,
+// or have it somewhere else than destinations says it goes now
+pub async fn clouds_behind(
+    file: Option<&cl_sync_cache::ToUpload>,
+    to_up: &toml::TomlUpload,
+    manifest_hash: &str,
+    destinations: &HashMap<String, String>,
+) -> Result<Vec<String>> {
+    if !exists(file).await {
+        return Ok(to_up.clouds());
+    }
+    let Some(file) = file else {
+        return Ok(to_up.clouds());
+    };
+
+    // Entries from a cache without per-cloud state fall back to the modified time
+    if file.clouds.is_empty() {
+        let cache_last_up = get_last_update_from_cache(Some(file)).await?;
+        if compare_last_update(cache_last_up, &to_up.file_or_dir_path).await? {
+            return Ok(to_up.clouds());
+        }
+        return Ok(vec![]);
+    }
+
+    Ok(to_up
+        .clouds()
+        .into_iter()
+        .filter(|cloud| match file.clouds.get(cloud) {
+            Some(state) => {
+                state.manifest_hash.as_deref() != Some(manifest_hash)
+                    || state
+                        .destination
+                        .as_ref()
+                        .zip(destinations.get(cloud))
+                        .is_some_and(|(synced_to, goes_to)| synced_to != goes_to)
+            }
+            None => true,
+        })
+        .collect())
+}
+
+// Record the outcome of every cloud an entry was synced to,
+// archive_hashes has the archive uploaded to each cloud for entries with one
+pub async fn save_cloud_results_to_cache(
+    key: &str,
+    to_up: &toml::TomlUpload,
+    manifest_hash: &str,
+    archive_hashes: &HashMap<String, String>,
+    started_at: DateTime<Local>,
+    results: &CloudResults,
     parsed_toml: &toml::TomlParser,
 ) -> Result<()> {
+    let destinations = destinations(parsed_toml, to_up).await;
+    let _guard = CACHE_WRITE_LOCK.lock().await;
     let cache = load(parsed_toml).await?;
 
-    // Add a new file to the cache
-    let new_file = cl_sync_cache::ToUpload {
-        file_path: file_or_dir_path.to_string(),
-        last_saved: Local::now(),
-    };
-    cache.insert(new_file).await;
+    // Add a new file to the cache or update the existing one
+    let file_path = sys_ops::canonical_path(&to_up.file_or_dir_path).await;
+    let mut file = cache
+        .get(key)
+        .await
+        .unwrap_or_else(|| cl_sync_cache::ToUpload {
+            file_path: file_path.to_string(),
+            last_saved: started_at,
+            clouds: HashMap::new(),
+        });
+    file.file_path = file_path;
+    for (cloud, result) in results {
+        let state = file.clouds.entry(cloud.to_string()).or_default();
+        state.last_attempt = Some(started_at);
+        match result {
+            Ok(()) => {
+                state.last_synced = Some(started_at);
+                state.manifest_hash = Some(manifest_hash.to_string());
+                state.archive_hash = archive_hashes.get(cloud).cloned();
+                state.destination = destinations.get(cloud).cloned();
+                state.last_error = None;
+            }
+            Err(e) => state.last_error = Some(e.to_string()),
+        }
+    }
+    if results.iter().any(|(_, result)| result.is_ok()) {
+        file.last_saved = started_at;
+    }
+    cache.insert(key, file).await;
 
     // Save updated cache
     cache.save_to_file().await?;
 
     Ok(())
 }
+
+pub async fn save_schedule_run_to_cache(
+    key: &str,
+    run: cl_sync_cache::ScheduleRun,
+    parsed_toml: &toml::TomlParser,
+) -> Result<()> {
+    let _guard = CACHE_WRITE_LOCK.lock().await;
+    let cache = load(parsed_toml).await?;
+    cache.insert_schedule(key, run).await;
+    cache.save_to_file().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::*;
+    use crate::test_fixtures::{entry, parser_with_clouds, provider};
+
+    fn synced(manifest_hash: &str) -> cl_sync_cache::CloudSyncState {
+        cl_sync_cache::CloudSyncState {
+            last_synced: Some(Local::now()),
+            manifest_hash: Some(manifest_hash.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clouds_behind() -> Result<()> {
+        let to_up = entry("/home/user/vault", &["dge", "ode_rcl", "dg"]);
+        let destinations = HashMap::new();
+        assert_eq!(
+            clouds_behind(None, &to_up, "abc", &destinations).await?,
+            to_up.clouds()
+        );
+
+        let mut clouds = HashMap::new();
+        clouds.insert("dge".to_string(), synced("abc"));
+        // ode_rcl failed its last sync and still has the old state
+        let mut failed = synced("old");
+        failed.last_error = Some("quota exceeded".to_string());
+        clouds.insert("ode_rcl".to_string(), failed);
+        let file = cl_sync_cache::ToUpload {
+            file_path: to_up.file_or_dir_path.clone(),
+            last_saved: Local::now(),
+            clouds,
+        };
+
+        // dg was never synced
+        assert_eq!(
+            clouds_behind(Some(&file), &to_up, "abc", &destinations).await?,
+            vec!["ode_rcl".to_string(), "dg".to_string()]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_changed_destination_is_behind() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut to_up = entry("/home/user/vault", &["dge"]);
+        let mut dge = provider("dge");
+        dge.backend_options
+            .insert("drive_use_trash".to_string(), serde_json::json!(false));
+        let settings = |to_up: &toml::TomlUpload| {
+            parser_with_clouds(
+                &[("vault", to_up.clone())],
+                &[dge.clone()],
+                &tmp.path().join("cache"),
+            )
+        };
+
+        let before = destinations(&settings(&to_up), &to_up).await;
+        assert_eq!(before["dge"], "dge:desk/Vault");
+        let mut state = synced("abc");
+        state.destination = Some(before["dge"].to_string());
+        let file = cl_sync_cache::ToUpload {
+            file_path: to_up.file_or_dir_path.clone(),
+            last_saved: Local::now(),
+            clouds: HashMap::from([("dge".to_string(), state)]),
+        };
+        assert!(clouds_behind(Some(&file), &to_up, "abc", &before)
+            .await?
+            .is_empty());
+
+        // the target dir changed, nothing was uploaded there yet
+        to_up.upload_to_clouds = vec![toml::UploadTarget::Table(toml::TargetSettings {
+            cloud: "dge".to_string(),
+            dir: Some("Backups/Vault".to_string()),
+            ..Default::default()
+        })];
+        let after = destinations(&settings(&to_up), &to_up).await;
+        assert_eq!(
+            clouds_behind(Some(&file), &to_up, "abc", &after).await?,
+            vec!["dge".to_string()]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use anyhow::{anyhow, Context, Result};
+use hashbrown::HashMap;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+use crate::cl_sync::cache;
+use crate::operations::cl_sync_cache::CacheFile;
+use crate::operations::{sys_ops, toml};
+
+// `cl_sync cache list|prune|forget|reset|export|import`
+
+async fn upload_list(parsed_toml: &toml::TomlParser) -> Result<HashMap<String, toml::TomlUpload>> {
+    match parsed_toml
+        .get_section_from_toml(toml::TomlSection::Upload)
+        .await
+    {
+        Ok(toml::TomlToParse::Upload(dir)) => Ok(dir),
+        _ => Err(anyhow!("Unexpected section type for upload list")),
+    }
+}
+
+pub async fn list(parsed_toml: &toml::TomlParser) -> Result<()> {
+    let upload_list = upload_list(parsed_toml).await?;
+    let cache = cache::load(parsed_toml).await?;
+    let orphans = orphan_keys(&cache.keys().await, &upload_list);
+
+    for key in cache.keys().await {
+        let Some(file) = cache.get(&key).await else {
+            continue;
+        };
+        let orphan = if orphans.contains(&key) {
+            " (not in upload.toml)"
+        } else {
+            ""
+        };
+        println!(
+            "[upload.{}] {}{}\n  last saved {}",
+            key,
+            file.file_path,
+            orphan,
+            file.last_saved.format("%Y-%m-%d %H:%M:%S")
+        );
+
+        let mut clouds: Vec<&String> = file.clouds.keys().collect();
+        clouds.sort();
+        for cloud in clouds {
+            let state = &file.clouds[cloud];
+            let last_synced = state.last_synced.map_or("never".to_string(), |at| {
+                at.format("%Y-%m-%d %H:%M:%S").to_string()
+            });
+            println!("  {:<12} synced {}", cloud, last_synced);
+        }
+    }
+    Ok(())
+}
+
+// Drop cache records of entries that were removed from upload.toml
+pub async fn prune(parsed_toml: &toml::TomlParser) -> Result<()> {
+    let upload_list = upload_list(parsed_toml).await?;
+    let cache = cache::load(parsed_toml).await?;
+
+    let orphans = orphan_keys(&cache.keys().await, &upload_list);
+    if orphans.is_empty() {
+        println!("Nothing to prune.");
+        return Ok(());
+    }
+    for key in &orphans {
+        cache.remove(key).await;
+        cache.remove_schedule(key).await;
+        println!("Pruned {}", key);
+    }
+    cache.save_to_file().await
+}
+
+// Forget one entry, given by its [upload.<id>] name or its path,
+// so the next sync uploads it again
+pub async fn forget(parsed_toml: &toml::TomlParser, entry: &str) -> Result<()> {
+    let upload_list = upload_list(parsed_toml).await?;
+    let cache = cache::load(parsed_toml).await?;
+
+    let key = if upload_list.contains_key(entry) {
+        entry.to_string()
+    } else {
+        // find the record of that path
+        let canonical = sys_ops::canonical_path(entry).await;
+        let mut key = entry.to_string();
+        for cache_key in cache.keys().await {
+            if let Some(file) = cache.get(&cache_key).await {
+                if file.file_path == canonical {
+                    key = cache_key;
+                    break;
+                }
+            }
+        }
+        key
+    };
+    let key = key.as_str();
+    let removed = cache.remove(key).await;
+    let removed_schedule = cache.remove_schedule(key).await;
+    if removed.is_none() && removed_schedule.is_none() {
+        return Err(anyhow!("{} is not in the cache", entry));
+    }
+    cache.save_to_file().await?;
+    println!("Forgot {}", key);
+    Ok(())
+}
+
+pub async fn reset(parsed_toml: &toml::TomlParser) -> Result<()> {
+    let cache = cache::load(parsed_toml).await?;
+    cache.clear().await;
+    cache.save_to_file().await?;
+    println!("Cache reset, the next sync uploads everything again.");
+    Ok(())
+}
+
+pub async fn export_json(parsed_toml: &toml::TomlParser) -> Result<()> {
+    println!("{}", export(parsed_toml).await?);
+    Ok(())
+}
+
+async fn export(parsed_toml: &toml::TomlParser) -> Result<String> {
+    let cache = cache::load(parsed_toml).await?;
+    let cache_file = cache.to_cache_file().await;
+    Ok(serde_json::to_string_pretty(&cache_file)?)
+}
+
+// Import records exported with `cache export --json`, `-` reads stdin
+pub async fn import_json(parsed_toml: &toml::TomlParser, path: &Path) -> Result<()> {
+    let json = if path == Path::new("-") {
+        let mut json = String::new();
+        tokio::io::stdin().read_to_string(&mut json).await?;
+        json
+    } else {
+        tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?
+    };
+    let imported = import(parsed_toml, &json).await?;
+    println!("Imported {} entries.", imported);
+    Ok(())
+}
+
+// Merge the exported records into the cache, the number of entries imported
+async fn import(parsed_toml: &toml::TomlParser, json: &str) -> Result<usize> {
+    let cache_file: CacheFile =
+        serde_json::from_str(json).context("Failed to parse exported cache")?;
+    let imported = cache_file.data.len();
+
+    let cache = cache::load(parsed_toml).await?;
+    cache.merge(cache_file).await;
+    cache.save_to_file().await?;
+    Ok(imported)
+}
+
+// Cache keys that don't belong to any upload entry
+fn orphan_keys(keys: &[String], upload_list: &HashMap<String, toml::TomlUpload>) -> Vec<String> {
+    keys.iter()
+        .filter(|key| !upload_list.contains_key(*key))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod cache_commands_test {
+    use super::*;
+    use crate::operations::cl_sync_cache::ToUpload;
+    use crate::test_fixtures::{entry, parser, write_cache};
+    use chrono::Local;
+
+    fn record(path: &str) -> ToUpload {
+        ToUpload {
+            file_path: path.to_string(),
+            last_saved: Local::now(),
+            clouds: HashMap::new(),
+        }
+    }
+
+    async fn cached_keys(parsed_toml: &toml::TomlParser) -> Vec<String> {
+        cache::load(parsed_toml).await.unwrap().keys().await
+    }
+
+    #[test]
+    fn test_orphan_keys() {
+        let mut upload_list = HashMap::new();
+        upload_list.insert("vault".to_string(), entry("/home/user/vault", &["dge"]));
+
+        let keys = vec!["old".to_string(), "vault".to_string()];
+        assert_eq!(orphan_keys(&keys, &upload_list), vec!["old".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_export_import_round_trip() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let entries = [("vault", entry("/home/user/vault", &["dge"]))];
+        let from_path = tmp.path().join("from.bin");
+        write_cache(
+            &from_path,
+            &[
+                ("vault", record("/home/user/vault")),
+                ("old", record("/old")),
+            ],
+        )
+        .await;
+        let to_path = tmp.path().join("to.bin");
+        write_cache(&to_path, &[("notes", record("/home/user/notes"))]).await;
+
+        let json = export(&parser(&entries, &from_path)).await?;
+        let to_toml = parser(&entries, &to_path);
+        assert_eq!(import(&to_toml, &json).await?, 2);
+
+        // merged with the records already there and saved
+        assert_eq!(cached_keys(&to_toml).await, vec!["notes", "old", "vault"]);
+        let vault = cache::load(&to_toml).await?.get("vault").await.unwrap();
+        assert_eq!(vault.file_path, "/home/user/vault");
+
+        assert!(import(&to_toml, "not json").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forget() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let notes_path = tmp.path().join("notes");
+        std::fs::create_dir(&notes_path)?;
+        let notes_path = notes_path.to_string_lossy().to_string();
+        let cache_path = tmp.path().join("cache.bin");
+        write_cache(
+            &cache_path,
+            &[
+                ("vault", record("/home/user/vault")),
+                ("notes", record(&sys_ops::canonical_path(&notes_path).await)),
+            ],
+        )
+        .await;
+        let parsed_toml = parser(
+            &[
+                ("vault", entry("/home/user/vault", &["dge"])),
+                ("notes", entry(&notes_path, &["dge"])),
+            ],
+            &cache_path,
+        );
+
+        // by its entry name
+        forget(&parsed_toml, "vault").await?;
+        assert_eq!(cached_keys(&parsed_toml).await, vec!["notes"]);
+        // by its path
+        forget(&parsed_toml, &notes_path).await?;
+        assert!(cached_keys(&parsed_toml).await.is_empty());
+
+        assert!(forget(&parsed_toml, "vault").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cache_path = tmp.path().join("cache.bin");
+        write_cache(
+            &cache_path,
+            &[
+                ("vault", record("/home/user/vault")),
+                ("old", record("/old")),
+            ],
+        )
+        .await;
+        let parsed_toml = parser(
+            &[("vault", entry("/home/user/vault", &["dge"]))],
+            &cache_path,
+        );
+
+        prune(&parsed_toml).await?;
+        assert_eq!(cached_keys(&parsed_toml).await, vec!["vault"]);
+        // nothing left to prune
+        prune(&parsed_toml).await?;
+        assert_eq!(cached_keys(&parsed_toml).await, vec!["vault"]);
+        Ok(())
+    }
+}
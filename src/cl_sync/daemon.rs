@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use hashbrown::{HashMap, HashSet};
+use tokio::task::{Id, JoinSet};
+use tracing::debug;
+
+use crate::cl_sync;
+use crate::cl_sync::cache;
+use crate::cl_sync::schedule::Schedule;
+use crate::operations::cl_sync_cache::ScheduleRun;
+use crate::operations::toml;
+
+// Longest time the daemon sleeps before looking at the clock again.
+// Timers don't advance while the machine is suspended, so waking up
+// regularly is what lets missed runs be caught up after a resume.
+const MAX_SLEEP_SECS: i64 = 60;
+// Delay before an entry whose run failed is run again
+const FAILED_RETRY_SECS: i64 = 300;
+
+// Runs every upload entry that has a `schedule` on its own cadence
+// until interrupted. An entry is never started again while it is still running.
+pub async fn begin_daemon(parsed_toml: &toml::TomlParser) -> Result<()> {
+    let upload_list = match parsed_toml
+        .get_section_from_toml(toml::TomlSection::Upload)
+        .await
+    {
+        Ok(toml::TomlToParse::Upload(dir)) => dir,
+        _ => return Err(anyhow!("Unexpected section type for upload list")),
+    };
+
+    let mut schedules: HashMap<String, Schedule> = HashMap::new();
+    for (key, to_up) in &upload_list {
+        match &to_up.schedule {
+            Some(schedule) => {
+                let schedule = Schedule::parse(schedule)
+                    .with_context(|| format!("Invalid schedule for [upload.{}]", key))?;
+                schedules.insert(key.to_string(), schedule);
+            }
+            None => debug!("[upload.{}] has no schedule, skipping", key),
+        }
+    }
+    if schedules.is_empty() {
+        return Err(anyhow!(
+            "No upload entry has a schedule in the upload.toml file"
+        ));
+    }
+
+    let bwlimit = cl_sync::sync_config(parsed_toml).await?.bwlimit;
+    let session = cl_sync::SyncSession::new().with_bwlimit(bwlimit);
+    let mut running: JoinSet<(String, DateTime<Local>, Result<bool>)> = JoinSet::new();
+    let mut running_keys: HashSet<String> = HashSet::new();
+    let mut task_keys: HashMap<Id, String> = HashMap::new();
+    // entries whose last run failed, they stay due and are retried from then on
+    let mut retry_at: HashMap<String, DateTime<Local>> = HashMap::new();
+
+    loop {
+        let now = Local::now();
+        let mut next_wake = now + chrono::Duration::seconds(MAX_SLEEP_SECS);
+        // without the cache the last runs are unknown, nothing starts until it loads again
+        let cache = match cache::load(parsed_toml).await {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                eprintln!("Failed to load the cache, retrying: {:?}", e);
+                None
+            }
+        };
+        let due_checks = match &cache {
+            Some(cache) => schedules.iter().map(|entry| (entry, cache)).collect(),
+            None => vec![],
+        };
+
+        // entries whose next run can't be worked out, they are run no more
+        let mut dropped: Vec<String> = vec![];
+        for ((key, schedule), cache) in due_checks {
+            if running_keys.contains(key) {
+                continue;
+            }
+            let to_up = &upload_list[key];
+            let last_run = cache.get_schedule(key).await.and_then(|run| run.last_run);
+            let mut next_run = match schedule.next_run(last_run, now) {
+                Ok(next_run) => next_run,
+                Err(e) => {
+                    eprintln!("Dropping the schedule of [upload.{}]: {:?}", key, e);
+                    dropped.push(key.to_string());
+                    continue;
+                }
+            };
+            if let Some(retry) = retry_at.get(key) {
+                next_run = next_run.max(*retry);
+            }
+
+            if next_run > now {
+                next_wake = next_wake.min(next_run);
+                continue;
+            }
+
+            println!("Running [upload.{}] (due {})", key, next_run);
+            running_keys.insert(key.to_string());
+            let key = key.to_string();
+            let to_up = to_up.clone();
+            let parsed_toml = parsed_toml.clone();
+            let session = session.clone();
+            let task_key = key.clone();
+            let task = running.spawn(async move {
+                let result = cl_sync::sync_if_modified(&parsed_toml, &key, &to_up, &session)
+                    .await
+                    .and_then(|outcome| cl_sync::entry_result(&to_up, outcome));
+                (key, now, result)
+            });
+            task_keys.insert(task.id(), task_key);
+        }
+        for key in dropped {
+            schedules.remove(&key);
+        }
+        if schedules.is_empty() {
+            break;
+        }
+
+        let sleep_for = (next_wake - now).to_std().unwrap_or_default();
+        tokio::select! {
+            Some(joined) = running.join_next_with_id(), if !running.is_empty() => {
+                let (key, started_at, result) = match joined {
+                    Ok((id, (key, started_at, result))) => {
+                        task_keys.remove(&id);
+                        (key, started_at, result)
+                    }
+                    Err(e) => {
+                        let key = task_keys.remove(&e.id()).unwrap_or_default();
+                        let result = Err(anyhow!("the sync task failed: {}", e));
+                        (key, Local::now(), result)
+                    }
+                };
+                running_keys.remove(&key);
+                match result {
+                    Ok(true) => println!("Synced [upload.{}]", key),
+                    Ok(false) => println!("[upload.{}] is up to date", key),
+                    Err(e) => {
+                        let retry = Local::now() + chrono::Duration::seconds(FAILED_RETRY_SECS);
+                        eprintln!(
+                            "Failed to sync [upload.{}], retrying at {}: {:?}",
+                            key, retry, e
+                        );
+                        retry_at.insert(key, retry);
+                        continue;
+                    }
+                }
+                retry_at.remove(&key);
+
+                let next_run = match schedules[&key].next_run(Some(started_at), Local::now()) {
+                    Ok(next_run) => Some(next_run),
+                    Err(e) => {
+                        eprintln!("Dropping the schedule of [upload.{}]: {:?}", key, e);
+                        schedules.remove(&key);
+                        None
+                    }
+                };
+                let run = ScheduleRun {
+                    last_run: Some(started_at),
+                    next_run,
+                };
+                if let Err(e) = cache::save_schedule_run_to_cache(&key, run, parsed_toml).await {
+                    eprintln!("Failed to save the last run of [upload.{}]: {:?}", key, e);
+                }
+            }
+            _ = tokio::time::sleep(sleep_for) => {}
+            _ = tokio::signal::ctrl_c() => {
+                println!("Stopping daemon.");
+                break;
+            }
+        }
+    }
+
+    running.abort_all();
+    while running.join_next().await.is_some() {}
+    // Dismount and stop rclone when done
+    session.finish().await?;
+    if schedules.is_empty() {
+        return Err(anyhow!("No schedule is left to run"));
+    }
+    Ok(())
+}
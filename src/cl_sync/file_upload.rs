@@ -0,0 +1,262 @@
+use std::path::Path;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::cl_sync::{run_job, snapshot, SyncSession};
+use crate::error::{ClSyncError, TomlError};
+use crate::operations::rc::{JobOptions, RcClient, StatItem};
+use crate::operations::{rclone, toml};
+
+// Where a single file entry is copied from and to: the dir and name of
+// file_or_dir_path locally, <destination>/<remote_file_name> on the cloud,
+// <destination>/<snapshot>/<remote_file_name> in snapshot mode
+#[derive(Debug, PartialEq)]
+pub struct FileCopy {
+    pub src_fs: String,
+    pub src_file: String,
+    pub dst_fs: String,
+    pub dst_file: String,
+    pub mode: toml::UploadMode,
+}
+
+impl FileCopy {
+    pub fn new(
+        key: &str,
+        to_up: &toml::TomlUpload,
+        remote: &toml::CloudProviders,
+        snapshot: &str,
+    ) -> Result<Self, TomlError> {
+        let path = Path::new(&to_up.file_or_dir_path);
+        let Some(file_name) = path.file_name() else {
+            return Err(TomlError::InvalidSetting {
+                section: format!("upload.{}", key),
+                setting: "file_or_dir_path",
+                reason: "has no file name".to_string(),
+            });
+        };
+        let src_file = file_name.to_string_lossy().to_string();
+        let src_fs = path
+            .parent()
+            .map_or(String::new(), |dir| dir.to_string_lossy().to_string());
+
+        let remote_file_name = to_up.remote_file_name.as_deref().unwrap_or(&src_file);
+        let destination = snapshot::destination(to_up, remote, snapshot);
+        Ok(Self {
+            dst_file: destination.file(remote_file_name),
+            dst_fs: destination.fs,
+            src_fs,
+            src_file,
+            mode: to_up.mode(&remote.cloud_name),
+        })
+    }
+}
+
+// Upload the file unless the cloud already has the same one,
+// a file that is moved is always moved so it is gone locally afterwards
+// and a snapshot always goes to a new dir
+pub async fn upload_file(
+    session: &SyncSession,
+    copy: &FileCopy,
+    job: JobOptions,
+    deadline: Option<Instant>,
+) -> Result<(), ClSyncError> {
+    let may_skip = matches!(copy.mode, toml::UploadMode::Sync | toml::UploadMode::Copy);
+    if may_skip && already_uploaded(&session.client, copy).await {
+        println!(
+            "{}{} is already up to date, skipping the upload",
+            copy.dst_fs, copy.dst_file
+        );
+        return Ok(());
+    }
+    run_job(
+        session,
+        rclone::transfer_file(
+            &session.client,
+            copy.mode,
+            copy.src_fs.to_string(),
+            copy.src_file.to_string(),
+            copy.dst_fs.to_string(),
+            copy.dst_file.to_string(),
+            job,
+        ),
+        deadline,
+    )
+    .await
+}
+
+// Failing to find out counts as not uploaded, the copy settles it
+async fn already_uploaded(client: &RcClient, copy: &FileCopy) -> bool {
+    let (local, remote) = futures::join!(
+        rclone::stat(client, &copy.src_fs, &copy.src_file),
+        rclone::stat(client, &copy.dst_fs, &copy.dst_file)
+    );
+    match (local, remote) {
+        (Ok(Some(local)), Ok(Some(remote))) => is_identical(&local, &remote),
+        (Err(e), _) | (_, Err(e)) => {
+            debug!(
+                "Failed to compare {} with the cloud: {:?}",
+                copy.src_file, e
+            );
+            false
+        }
+        _ => false,
+    }
+}
+
+// Same size and the same hash for every type both sides support.
+// Files of a remote without any hash in common can't be told apart.
+fn is_identical(local: &StatItem, remote: &StatItem) -> bool {
+    if local.is_dir || remote.is_dir || local.size < 0 || local.size != remote.size {
+        return false;
+    }
+    let common: Vec<(&String, &String)> = local
+        .hashes
+        .iter()
+        .filter(|(_, hash)| !hash.is_empty())
+        .filter_map(|(kind, hash)| {
+            remote
+                .hashes
+                .get(kind)
+                .filter(|remote_hash| !remote_hash.is_empty())
+                .map(|remote_hash| (hash, remote_hash))
+        })
+        .collect();
+    !common.is_empty()
+        && common
+            .iter()
+            .all(|(hash, remote_hash)| hash.eq_ignore_ascii_case(remote_hash))
+}
+
+#[cfg(test)]
+mod file_upload_test {
+    use super::*;
+    use crate::test_fixtures::{entry, provider, RcStub};
+    use serde_json::{json, Value};
+
+    fn item(size: i64, hashes: &[(&str, &str)]) -> StatItem {
+        StatItem {
+            name: "pw.kdbx".to_string(),
+            size,
+            hashes: hashes
+                .iter()
+                .map(|(kind, hash)| (kind.to_string(), hash.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    const SNAPSHOT: &str = "2026-10-19_031500";
+
+    #[test]
+    fn test_file_copy_paths() -> anyhow::Result<()> {
+        let mut to_up = entry("/home/user/pw.kdbx", &["dge"]);
+        to_up.upload_to_cloud_dir = "/Keys/".to_string();
+        assert_eq!(
+            FileCopy::new("pw", &to_up, &provider("dge"), SNAPSHOT)?,
+            FileCopy {
+                src_fs: "/home/user".to_string(),
+                src_file: "pw.kdbx".to_string(),
+                dst_fs: "dge:".to_string(),
+                dst_file: "desk/Keys/pw.kdbx".to_string(),
+                mode: toml::UploadMode::Sync,
+            }
+        );
+
+        to_up.upload_to_cloud_dir = String::new();
+        to_up.remote_file_name = Some("passwords.kdbx".to_string());
+        assert_eq!(
+            FileCopy::new("pw", &to_up, &provider("dge"), SNAPSHOT)?.dst_file,
+            "desk/passwords.kdbx"
+        );
+
+        to_up.mode = toml::UploadMode::Snapshot;
+        assert_eq!(
+            FileCopy::new("pw", &to_up, &provider("dge"), SNAPSHOT)?.dst_file,
+            "desk/2026-10-19_031500/passwords.kdbx"
+        );
+
+        let to_up = entry("/", &["dge"]);
+        assert!(FileCopy::new("pw", &to_up, &provider("dge"), SNAPSHOT).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_identical() {
+        let local = item(10, &[("md5", "abc"), ("sha1", "def")]);
+        assert!(is_identical(&local, &item(10, &[("md5", "ABC")])));
+        assert!(!is_identical(&local, &item(10, &[("md5", "abd")])));
+        assert!(!is_identical(&local, &item(11, &[("md5", "abc")])));
+        // no hash in common, e.g. a crypt remote
+        assert!(!is_identical(&local, &item(10, &[])));
+        assert!(!is_identical(&local, &item(10, &[("md5", "")])));
+    }
+
+    // The daemon has the local pw.kdbx, and on the cloud the file remote_hash
+    // says or none. Copies run as job 7 and succeed.
+    fn daemon(remote_hash: Option<&'static str>) -> impl Fn(&str, &Value) -> Value {
+        move |command, params| match command {
+            "operations/stat" if params["fs"] == "/home/user" => json!({
+                "item": { "Name": "pw.kdbx", "Size": 10, "Hashes": { "md5": "abc" } }
+            }),
+            "operations/stat" => match remote_hash {
+                Some(hash) => json!({
+                    "item": { "Name": "pw.kdbx", "Size": 10, "Hashes": { "md5": hash } }
+                }),
+                None => json!({ "item": null }),
+            },
+            "operations/copyfile" => json!({ "jobid": 7 }),
+            "job/list" => json!({ "jobids": [7], "runningIds": [] }),
+            "job/status" => json!({ "id": 7, "finished": true, "success": true }),
+            _ => json!({}),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_file() -> anyhow::Result<()> {
+        let to_up = entry("/home/user/pw.kdbx", &["dge"]);
+        let copy = FileCopy::new("pw", &to_up, &provider("dge"), SNAPSHOT)?;
+        let job = JobOptions::background(Some("pw"));
+
+        // the cloud has the same file
+        let stub = RcStub::start(daemon(Some("ABC"))).await;
+        upload_file(&stub.session(), &copy, job.clone(), None).await?;
+        assert_eq!(stub.commands(), vec!["operations/stat", "operations/stat"]);
+        drop(stub);
+
+        // the cloud has another one
+        let stub = RcStub::start(daemon(Some("abd"))).await;
+        upload_file(&stub.session(), &copy, job.clone(), None).await?;
+        assert_eq!(
+            stub.params("operations/copyfile"),
+            vec![json!({
+                "srcFs": "/home/user",
+                "srcFile": "pw.kdbx",
+                "dstFs": "dge:",
+                "dstFile": "desk/Vault/pw.kdbx",
+                "_async": true,
+                "_group": "pw",
+            })]
+        );
+        assert!(stub.commands().contains(&"job/status".to_string()));
+        drop(stub);
+
+        // none on the cloud yet
+        let stub = RcStub::start(daemon(None)).await;
+        upload_file(&stub.session(), &copy, job.clone(), None).await?;
+        assert_eq!(stub.params("operations/copyfile").len(), 1);
+        drop(stub);
+
+        // a snapshot never skips
+        let mut to_up = to_up;
+        to_up.mode = toml::UploadMode::Snapshot;
+        let copy = FileCopy::new("pw", &to_up, &provider("dge"), SNAPSHOT)?;
+        let stub = RcStub::start(daemon(Some("abc"))).await;
+        upload_file(&stub.session(), &copy, job, None).await?;
+        assert!(!stub.commands().contains(&"operations/stat".to_string()));
+        assert_eq!(
+            stub.params("operations/copyfile")[0]["dstFile"],
+            "desk/Vault/2026-10-19_031500/pw.kdbx"
+        );
+        Ok(())
+    }
+}
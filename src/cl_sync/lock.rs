@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Context, Result};
+use home::home_dir;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+// Advisory lock that keeps two cl_sync runs from starting rclone,
+// mounting the same dirs and writing the cache at the same time.
+// It is a flock on a lock file that is never deleted, the kernel
+// releases it when the owner exits, even when it crashes. The file
+// holds the pid of the owner so the next run can say who it waits for.
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+// $XDG_RUNTIME_DIR/cl_sync.lock, or ~/.config/cl_sync/cl_sync.lock without a runtime dir
+pub fn lock_file_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("cl_sync.lock"),
+        None => {
+            let home_path = home_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
+            home_path.join(".config/cl_sync/cl_sync.lock")
+        }
+    }
+}
+
+impl InstanceLock {
+    pub async fn acquire(wait: bool) -> Result<Self> {
+        Self::acquire_at(lock_file_path(), wait).await
+    }
+
+    // Take the lock at path. When another live cl_sync holds it either
+    // fail right away or, with wait, poll until it is released.
+    pub async fn acquire_at(path: PathBuf, wait: bool) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut waiting = false;
+        loop {
+            match Self::try_acquire(&path)? {
+                Ok(file) => return Ok(Self { file, path }),
+                Err(pid) if wait => {
+                    if !waiting {
+                        println!("Waiting for another cl_sync (pid {}) to finish...", pid);
+                        waiting = true;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                }
+                Err(pid) => {
+                    return Err(anyhow!(
+                        "Another cl_sync (pid {}) is running, pass --wait to wait for it to finish",
+                        pid
+                    ))
+                }
+            }
+        }
+    }
+
+    // The locked file, or the pid of the owner when another process holds
+    // the lock ("?" while it hasn't written its pid yet)
+    fn try_acquire(path: &Path) -> Result<std::result::Result<File, String>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let owner = Self::owner(&mut file).map_or("?".to_string(), |pid| pid.to_string());
+                return Ok(Err(owner));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+            }
+        }
+        file.set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| write!(file, "{}", std::process::id()))
+            .and_then(|()| file.flush())
+            .with_context(|| format!("Failed to write lock file {}", path.display()))?;
+        debug!("Acquired lock {}", path.display());
+        Ok(Ok(file))
+    }
+
+    fn owner(file: &mut File) -> Option<u32> {
+        let mut pid = String::new();
+        file.rewind().ok()?;
+        file.read_to_string(&mut pid).ok()?;
+        pid.trim().parse().ok()
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // the file stays, closing it releases the lock
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+        debug!("Released lock {}", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod lock_test {
+    use super::*;
+
+    fn temp_lock_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cl_sync_{}_{}.lock", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_second_instance_is_refused() -> Result<()> {
+        let path = temp_lock_path("refused");
+        let lock = InstanceLock::acquire_at(path.clone(), false).await?;
+
+        let err = InstanceLock::acquire_at(path.clone(), false)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("pid {}", std::process::id())));
+
+        drop(lock);
+        // the file stays, the lock is free again
+        let lock = InstanceLock::acquire_at(path.clone(), false).await?;
+        drop(lock);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_lock_is_taken_over() -> Result<()> {
+        let path = temp_lock_path("stale");
+        // left behind by a crashed run, nobody holds the flock
+        std::fs::write(&path, "999999999")?;
+
+        let lock = InstanceLock::acquire_at(path.clone(), false).await?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            std::process::id().to_string()
+        );
+        drop(lock);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Result};
+use hashbrown::HashMap;
+use indicatif::HumanBytes;
+use std::path::Path;
+use tracing::debug;
+
+use crate::cl_sync::lock::InstanceLock;
+use crate::cl_sync::{sync_config, CloudResults, SyncSession};
+use crate::error::ClSyncError;
+use crate::operations::rc::{AboutResponse, RcClient};
+use crate::operations::rclone::{self, RcloneServer};
+use crate::operations::{sys_ops, toml};
+
+async fn cloud_providers(
+    parsed_toml: &toml::TomlParser,
+) -> Result<HashMap<String, toml::CloudProviders>> {
+    match parsed_toml
+        .get_section_from_toml(toml::TomlSection::CloudProviders)
+        .await
+    {
+        Ok(toml::TomlToParse::CloudProviders(cloud)) => Ok(cloud),
+        Ok(_) => Err(anyhow!("Unexpected section type for cloud providers")),
+        Err(e) => Err(e),
+    }
+}
+
+// Bytes left on a remote, worked out from total and used when it
+// doesn't report free, None when it reports neither
+fn free_space(about: &AboutResponse) -> Option<u64> {
+    about.free.or_else(|| match (about.total, about.used) {
+        (Some(total), Some(used)) => Some(total.saturating_sub(used)),
+        _ => None,
+    })
+}
+
+// Free space of a remote when it is less than needed
+fn shortfall(needed: u64, about: &AboutResponse) -> Option<u64> {
+    free_space(about).filter(|free| *free < needed)
+}
+
+// Compare what an upload of the entry adds with the space left on every
+// cloud before anything is mounted: the size of the local tree less what
+// its destination already holds, which is only listed when the local
+// tree doesn't fit as a whole. Clouds that can't report their space
+// are uploaded to anyway. With
+// quota_check = "abort" clouds that are too full get an error in results
+// and are left out of the returned list.
+pub async fn preflight(
+    parsed_toml: &toml::TomlParser,
+    client: &RcClient,
+    to_up: &toml::TomlUpload,
+    clouds: &[String],
+    results: &mut CloudResults,
+) -> Result<Vec<String>> {
+    let check = sync_config(parsed_toml).await?.quota_check;
+    if check == toml::QuotaCheck::Off {
+        return Ok(clouds.to_vec());
+    }
+    let path = Path::new(&to_up.file_or_dir_path);
+    let local_size = sys_ops::tree_size(path).await?;
+    let is_dir = sys_ops::is_dir(path.to_path_buf()).await?;
+    let providers = cloud_providers(parsed_toml).await?;
+
+    let mut fitting = vec![];
+    for cloud in clouds {
+        // unknown clouds are reported when mounting
+        let Some(provider) = providers.get(cloud) else {
+            fitting.push(cloud.to_string());
+            continue;
+        };
+        let (fs, _) = provider.remote_fs();
+        let about = match rclone::about(client, &fs).await {
+            Ok(about) => about,
+            Err(e) => {
+                debug!("Failed to get the quota of {}: {:?}", cloud, e);
+                fitting.push(cloud.to_string());
+                continue;
+            }
+        };
+        // the destination is only listed when what it holds could make the upload fit
+        if shortfall(local_size, &about).is_none() {
+            fitting.push(cloud.to_string());
+            continue;
+        }
+        let needed =
+            local_size.saturating_sub(uploaded_size(client, to_up, provider, is_dir).await);
+        let Some(free) = shortfall(needed, &about) else {
+            fitting.push(cloud.to_string());
+            continue;
+        };
+        let error = ClSyncError::InsufficientSpace {
+            cloud: cloud.to_string(),
+            needed,
+            free,
+        };
+        if check == toml::QuotaCheck::Abort {
+            eprintln!("Not uploading {}: {}", to_up.file_or_dir_name, error);
+            results.push((cloud.to_string(), Err(error)));
+        } else {
+            eprintln!("Warning: {}", error);
+            fitting.push(cloud.to_string());
+        }
+    }
+    Ok(fitting)
+}
+
+// Bytes the destination of the entry on a cloud holds, 0 before the
+// first upload and for snapshots, which always go to a new dir
+async fn uploaded_size(
+    client: &RcClient,
+    to_up: &toml::TomlUpload,
+    provider: &toml::CloudProviders,
+    is_dir: bool,
+) -> u64 {
+    if to_up.mode(&provider.cloud_name) == toml::UploadMode::Snapshot {
+        return 0;
+    }
+    let destination = toml::Destination::resolve(to_up, provider);
+    // a dir uploaded file by file, or a single file: the archive or the file itself
+    let size = match (&to_up.archive, is_dir) {
+        (None, true) => rclone::size(client, &destination.remote()).await,
+        (archive, _) => {
+            let local_name = Path::new(&to_up.file_or_dir_path)
+                .file_name()
+                .map_or(String::new(), |name| name.to_string_lossy().to_string());
+            let name = match archive {
+                Some(archive) => archive.file_name(to_up),
+                None => to_up.remote_file_name.clone().unwrap_or(local_name),
+            };
+            rclone::stat(client, &destination.fs, &destination.file(&name))
+                .await
+                .map(|item| item.map_or(0, |item| item.size.max(0) as u64))
+        }
+    };
+    size.unwrap_or_else(|e| {
+        debug!(
+            "Failed to get the size of {} on {}: {:?}",
+            to_up.file_or_dir_name, provider.cloud_name, e
+        );
+        0
+    })
+}
+
+// `cl_sync quota`, uses the daemon of a running sync when there is one.
+// Starting and stopping its own daemon takes the instance lock,
+// so no sync starts using that daemon in between.
+pub async fn begin_quota(parsed_toml: &toml::TomlParser, wait: bool) -> Result<()> {
+    let providers = cloud_providers(parsed_toml).await?;
+    let session = SyncSession::new();
+    let lock = match RcloneServer::is_running().await {
+        true => None,
+        false => Some(InstanceLock::acquire(wait).await?),
+    };
+    // a sync that held the lock stops its daemon when it is done
+    let own_daemon = lock.is_some() && !RcloneServer::is_running().await;
+    if own_daemon {
+        session.start_rclone_server().await?;
+    }
+
+    let mut names: Vec<&String> = providers.keys().collect();
+    names.sort();
+    let mut rows = vec![];
+    for name in names {
+        let (fs, _) = providers[name].remote_fs();
+        rows.push((name, rclone::about(&session.client, &fs).await));
+    }
+
+    if own_daemon {
+        session.finish().await?;
+    }
+    drop(lock);
+    print_quota(&rows);
+    Ok(())
+}
+
+fn print_quota<E: std::fmt::Display>(rows: &[(&String, std::result::Result<AboutResponse, E>)]) {
+    let bytes = |value: Option<u64>| value.map_or("-".to_string(), |v| HumanBytes(v).to_string());
+    let cloud_width = rows
+        .iter()
+        .map(|(cloud, _)| cloud.len())
+        .max()
+        .unwrap_or(0)
+        .max("CLOUD".len());
+
+    println!(
+        "{:<cloud_width$}  {:>12}  {:>12}  {:>12}",
+        "CLOUD", "USED", "TOTAL", "FREE"
+    );
+    for (cloud, about) in rows {
+        match about {
+            Ok(about) => println!(
+                "{:<cloud_width$}  {:>12}  {:>12}  {:>12}",
+                cloud,
+                bytes(about.used),
+                bytes(about.total),
+                bytes(free_space(about)),
+            ),
+            Err(e) => println!("{:<cloud_width$}  failed: {}", cloud, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod quota_test {
+    use super::*;
+    use crate::test_fixtures::{entry, parser_with_clouds, provider, RcStub};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_uploaded_size() {
+        let stub = RcStub::start(|command, params| match command {
+            "operations/size" => json!({ "count": 2, "bytes": 80 }),
+            "operations/stat" if params["remote"] == "desk/Vault/vault.tar.zst" => {
+                json!({ "item": { "Name": "vault.tar.zst", "Size": 30 } })
+            }
+            _ => json!({ "item": null }),
+        })
+        .await;
+        let client = stub.client();
+        let dge = provider("dge");
+
+        let mut to_up = entry("/home/user/vault", &["dge"]);
+        assert_eq!(uploaded_size(&client, &to_up, &dge, true).await, 80);
+        assert_eq!(
+            stub.params("operations/size"),
+            vec![json!({ "fs": "dge:desk/Vault" })]
+        );
+
+        to_up.archive = Some(toml::ArchiveSettings {
+            name: None,
+            level: 3,
+        });
+        assert_eq!(uploaded_size(&client, &to_up, &dge, true).await, 30);
+
+        // not uploaded yet
+        let file = entry("/home/user/pw.kdbx", &["dge"]);
+        assert_eq!(uploaded_size(&client, &file, &dge, false).await, 0);
+
+        to_up.mode = toml::UploadMode::Snapshot;
+        let calls = stub.commands().len();
+        assert_eq!(uploaded_size(&client, &to_up, &dge, true).await, 0);
+        assert_eq!(stub.commands().len(), calls);
+    }
+
+    #[tokio::test]
+    async fn test_preflight_lists_the_destination_only_when_short() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let vault = tmp.path().join("vault");
+        std::fs::create_dir(&vault)?;
+        std::fs::write(vault.join("notes.md"), [0; 100])?;
+        let to_up = entry(&vault.to_string_lossy(), &["dge"]);
+        let parsed_toml = parser_with_clouds(
+            &[("vault", to_up.clone())],
+            &[provider("dge")],
+            &tmp.path().join("cache"),
+        );
+        let clouds = vec!["dge".to_string()];
+
+        let plenty = RcStub::start(|command, _| match command {
+            "operations/about" => json!({ "free": 1000 }),
+            _ => json!({ "count": 1, "bytes": 60 }),
+        })
+        .await;
+        let fitting =
+            preflight(&parsed_toml, &plenty.client(), &to_up, &clouds, &mut vec![]).await?;
+        assert_eq!(fitting, clouds);
+        assert_eq!(plenty.commands(), vec!["operations/about"]);
+
+        // 100 bytes don't fit in 50 free, but 60 of them are on the cloud already
+        let short = RcStub::start(|command, _| match command {
+            "operations/about" => json!({ "free": 50 }),
+            _ => json!({ "count": 1, "bytes": 60 }),
+        })
+        .await;
+        let mut results = vec![];
+        let fitting =
+            preflight(&parsed_toml, &short.client(), &to_up, &clouds, &mut results).await?;
+        assert_eq!(fitting, clouds);
+        assert!(results.is_empty());
+        assert_eq!(
+            short.commands(),
+            vec!["operations/about", "operations/size"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_shortfall() {
+        let about = AboutResponse {
+            total: Some(100),
+            used: Some(70),
+            ..Default::default()
+        };
+        assert_eq!(free_space(&about), Some(30));
+        assert_eq!(shortfall(40, &about), Some(30));
+        assert_eq!(shortfall(30, &about), None);
+
+        let about = AboutResponse {
+            total: Some(100),
+            used: Some(70),
+            free: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(shortfall(20, &about), Some(10));
+
+        // a remote without a quota
+        assert_eq!(shortfall(u64::MAX, &AboutResponse::default()), None);
+    }
+}
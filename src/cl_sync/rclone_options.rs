@@ -0,0 +1,263 @@
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+use crate::cl_sync::SyncSession;
+use crate::error::TomlError;
+use crate::operations::{rclone, toml};
+
+// rclone_options of the cloud, overridden by the ones of the entry
+pub fn merged(to_up: &toml::TomlUpload, provider: &toml::CloudProviders) -> Map<String, Value> {
+    let mut options = provider.rclone_options.clone();
+    for (name, value) in &to_up.rclone_options {
+        options.insert(name.to_string(), value.clone());
+    }
+    options
+}
+
+// Reject option names the running rclone doesn't know before anything
+// is mounted, a job would silently ignore them. rclone_options are
+// checked against the "main" block of options/get, the backend_options
+// of a cloud against the block of the backend of its remote.
+pub async fn check(
+    parsed_toml: &toml::TomlParser,
+    session: &SyncSession,
+    key: &str,
+    to_up: &toml::TomlUpload,
+    clouds: &[String],
+) -> Result<()> {
+    let providers = match parsed_toml
+        .get_section_from_toml(toml::TomlSection::CloudProviders)
+        .await
+    {
+        Ok(toml::TomlToParse::CloudProviders(cloud)) => cloud,
+        Ok(_) => return Err(anyhow!("Unexpected section type for cloud providers")),
+        Err(e) => return Err(e),
+    };
+    // unknown clouds are reported when mounting
+    let providers: Vec<&toml::CloudProviders> = clouds
+        .iter()
+        .filter_map(|cloud| providers.get(cloud))
+        .collect();
+    let mut sections = vec![(format!("upload.{}", key), &to_up.rclone_options)];
+    for provider in &providers {
+        sections.push((
+            format!("cloud_providers.{}", provider.cloud_name),
+            &provider.rclone_options,
+        ));
+    }
+    if sections.iter().all(|(_, options)| options.is_empty())
+        && providers
+            .iter()
+            .all(|provider| provider.backend_options.is_empty())
+    {
+        return Ok(());
+    }
+
+    let known = session.rclone_option_names().await?;
+    for (section, options) in sections {
+        check_names(&section, options, &known["main"])?;
+    }
+    for provider in providers {
+        if provider.backend_options.is_empty() {
+            continue;
+        }
+        let backend = rclone::remote_type(&session.client, provider.remote_name()).await?;
+        check_backend_names(
+            &format!("cloud_providers.{}", provider.cloud_name),
+            &provider.backend_options,
+            &backend,
+            known.get(&backend),
+        )?;
+    }
+    Ok(())
+}
+
+// Spelled differently, the same option: "size-only" is SizeOnly, "use-trash" use_trash
+fn same_name(a: &str, b: &str) -> bool {
+    a.replace(['-', '_'], "")
+        .eq_ignore_ascii_case(&b.replace(['-', '_'], ""))
+}
+
+fn check_names(
+    section: &str,
+    options: &Map<String, Value>,
+    known: &[String],
+) -> std::result::Result<(), TomlError> {
+    for name in options.keys() {
+        if known.contains(name) {
+            continue;
+        }
+        let hint = match known.iter().find(|known| same_name(known, name)) {
+            Some(known) => format!(", did you mean {}?", known),
+            None if name.contains(['-', '_']) => {
+                ", options of a backend go in backend_options of the cloud".to_string()
+            }
+            None => String::new(),
+        };
+        return Err(TomlError::InvalidSetting {
+            section: section.to_string(),
+            setting: "rclone_options",
+            reason: format!("unknown rclone option {}{}", name, hint),
+        });
+    }
+    Ok(())
+}
+
+// backend_options are named like the flags of the backend, drive_use_trash
+// is use_trash in the "drive" block. known is None when rclone lists no
+// options for the backend.
+fn check_backend_names(
+    section: &str,
+    options: &Map<String, Value>,
+    backend: &str,
+    known: Option<&Vec<String>>,
+) -> std::result::Result<(), TomlError> {
+    let invalid = |reason: String| TomlError::InvalidSetting {
+        section: section.to_string(),
+        setting: "backend_options",
+        reason,
+    };
+    for name in options.keys() {
+        let normalized = name.replace('-', "_");
+        let Some(option) = normalized.strip_prefix(&format!("{}_", backend)) else {
+            return Err(invalid(format!(
+                "{} is not an option of the {} backend of the remote, its options start with {}_",
+                name, backend, backend
+            )));
+        };
+        let known = known.ok_or_else(|| {
+            invalid(format!(
+                "rclone lists no options of the {} backend, is it up to date?",
+                backend
+            ))
+        })?;
+        if !known.iter().any(|known| same_name(known, option)) {
+            return Err(invalid(format!(
+                "unknown option {} of the {} backend",
+                name, backend
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod rclone_options_test {
+    use super::*;
+    use crate::test_fixtures::{entry, parser_with_clouds, provider, RcStub};
+    use serde_json::json;
+
+    fn options(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_entry_wins() {
+        let provider = toml::CloudProviders {
+            rclone_options: options(json!({ "Transfers": 4, "UseListR": true })),
+            ..Default::default()
+        };
+        let to_up = toml::TomlUpload {
+            rclone_options: options(json!({ "Transfers": 8, "CheckSum": true })),
+            ..Default::default()
+        };
+        assert_eq!(
+            Value::Object(merged(&to_up, &provider)),
+            json!({ "Transfers": 8, "UseListR": true, "CheckSum": true })
+        );
+    }
+
+    #[test]
+    fn test_check_names() {
+        let known = vec!["Transfers".to_string(), "SizeOnly".to_string()];
+        assert!(check_names("upload.vault", &options(json!({ "Transfers": 8 })), &known).is_ok());
+
+        let err = check_names(
+            "upload.vault",
+            &options(json!({ "size-only": true })),
+            &known,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("[upload.vault]"));
+        assert!(err.contains("did you mean SizeOnly"));
+
+        let err = check_names(
+            "cloud_providers.dge",
+            &options(json!({ "drive_use_trash": false })),
+            &known,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("go in backend_options of the cloud"));
+    }
+
+    #[test]
+    fn test_check_backend_names() {
+        let known = vec!["use_trash".to_string(), "chunk_size".to_string()];
+        let check = |options: Value, known: Option<&Vec<String>>| {
+            check_backend_names(
+                "cloud_providers.dge",
+                &self::options(options),
+                "drive",
+                known,
+            )
+            .map_err(|e| e.to_string())
+        };
+        assert!(check(json!({ "drive_use_trash": false }), Some(&known)).is_ok());
+        assert!(check(json!({ "drive-chunk-size": "64M" }), Some(&known)).is_ok());
+
+        let err = check(json!({ "onedrive_chunk_size": "64M" }), Some(&known)).unwrap_err();
+        assert!(err.contains("not an option of the drive backend"));
+        let err = check(json!({ "drive_use_trsh": false }), Some(&known)).unwrap_err();
+        assert!(err.contains("unknown option drive_use_trsh"));
+        let err = check(json!({ "drive_use_trash": false }), None).unwrap_err();
+        assert!(err.contains("lists no options of the drive backend"));
+    }
+
+    #[tokio::test]
+    async fn test_backend_options_reach_the_job() -> anyhow::Result<()> {
+        let stub = RcStub::start(|command, params| match command {
+            "options/get" => json!({
+                "main": { "Transfers": 4 },
+                "drive": { "use_trash": true, "chunk_size": 8388608 },
+            }),
+            "config/get" if params["name"] == "dge" => json!({ "type": "drive" }),
+            "sync/sync" => json!({ "jobid": 1 }),
+            _ => json!({ "error": "unexpected call", "status": 500 }),
+        })
+        .await;
+        let session = stub.session();
+        let tmp = tempfile::tempdir()?;
+        let mut dge = provider("dge");
+        dge.backend_options = options(json!({ "drive_use_trash": false }));
+        let to_up = entry("/home/user/vault", &["dge"]);
+        let parsed_toml = parser_with_clouds(
+            &[("vault", to_up.clone())],
+            &[dge.clone()],
+            &tmp.path().join("cache"),
+        );
+
+        check(
+            &parsed_toml,
+            &session,
+            "vault",
+            &to_up,
+            &["dge".to_string()],
+        )
+        .await?;
+        rclone::sync_dir(
+            &session.client,
+            toml::UploadMode::Sync,
+            to_up.file_or_dir_path.to_string(),
+            toml::Destination::resolve(&to_up, &dge).remote(),
+            crate::operations::rc::JobOptions::background(None),
+        )
+        .await?;
+        assert_eq!(
+            stub.params("sync/sync")[0]["dstFs"],
+            "dge,use_trash=false:desk/Vault"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::error::{ClSyncError, TomlError};
+use crate::operations::toml;
+
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+// backoff never grows past this
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(600);
+
+// How often an upload to one cloud is tried again after a transient failure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_RETRY_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Settings of the entry win over the ones of the cloud provider
+    pub fn for_cloud(
+        key: &str,
+        to_up: &toml::TomlUpload,
+        provider: &toml::CloudProviders,
+    ) -> Result<Self, TomlError> {
+        let retries = to_up
+            .retries
+            .or(provider.retries)
+            .unwrap_or(DEFAULT_RETRIES);
+
+        let (section, retry_backoff) = match (&to_up.retry_backoff, &provider.retry_backoff) {
+            (Some(backoff), _) => (format!("upload.{}", key), backoff),
+            (None, Some(backoff)) => (format!("cloud_providers.{}", provider.cloud_name), backoff),
+            (None, None) => {
+                return Ok(Self {
+                    retries,
+                    backoff: DEFAULT_RETRY_BACKOFF,
+                })
+            }
+        };
+        let backoff = toml::parse_duration_setting(&section, "retry_backoff", retry_backoff)?;
+        Ok(Self { retries, backoff })
+    }
+
+    // Delay before retry number `retry` (1 based), doubling every time
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.backoff
+            .checked_mul(factor)
+            .unwrap_or(MAX_RETRY_BACKOFF)
+            .min(MAX_RETRY_BACKOFF)
+    }
+}
+
+// Run an upload until it succeeds, fails with an error that isn't worth
+// retrying, used up the retries of the policy or the next retry would
+// only start after the deadline
+pub async fn with_retries<F, Fut>(
+    label: &str,
+    policy: RetryPolicy,
+    deadline: Option<Instant>,
+    mut run: F,
+) -> Result<(), ClSyncError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), ClSyncError>>,
+{
+    let mut retry = 0;
+    loop {
+        match run().await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is_retryable() && retry < policy.retries => {
+                retry += 1;
+                let delay = policy.delay(retry);
+                if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+                    return Err(e);
+                }
+                println!(
+                    "{} failed: {}, retry {}/{} in {}",
+                    label,
+                    e,
+                    retry,
+                    policy.retries,
+                    humantime::format_duration(delay)
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod retry_test {
+    use super::*;
+    use crate::error::RcloneError;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn failed_job(message: &str) -> ClSyncError {
+        ClSyncError::Job {
+            job_id: 1,
+            error: RcloneError::from_message(message),
+        }
+    }
+
+    #[test]
+    fn test_policy_for_cloud() -> anyhow::Result<()> {
+        let provider = toml::CloudProviders {
+            cloud_name: "dge".to_string(),
+            retries: Some(5),
+            retry_backoff: Some("30s".to_string()),
+            ..Default::default()
+        };
+        let mut to_up = toml::TomlUpload::default();
+        assert_eq!(
+            RetryPolicy::for_cloud("vault", &to_up, &provider)?,
+            RetryPolicy {
+                retries: 5,
+                backoff: Duration::from_secs(30)
+            }
+        );
+
+        to_up.retries = Some(1);
+        to_up.retry_backoff = Some("nonsense".to_string());
+        let err = RetryPolicy::for_cloud("vault", &to_up, &provider).unwrap_err();
+        assert!(err.to_string().contains("[upload.vault]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delay_doubles_up_to_max() {
+        let policy = RetryPolicy {
+            retries: 20,
+            backoff: Duration::from_secs(10),
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(10));
+        assert_eq!(policy.delay(3), Duration::from_secs(40));
+        assert_eq!(policy.delay(20), MAX_RETRY_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_are_not_retried() {
+        let policy = RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(1),
+        };
+
+        let attempts = AtomicU32::new(0);
+        let result = with_retries("vault to dge", policy, None, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(failed_job("connection reset by peer"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+        let attempts = AtomicU32::new(0);
+        let result = with_retries("vault to dge", policy, None, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(failed_job("couldn't fetch token: invalid_grant"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use croner::Cron;
+use std::str::FromStr;
+use std::time::Duration;
+
+// How often an upload entry runs in `cl_sync daemon`.
+// Set with `schedule` on an upload entry, either an interval ("10m", "1h 30m")
+// or a cron expression ("0 3 * * *").
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Box<Cron>),
+}
+
+impl Schedule {
+    pub fn parse(schedule: &str) -> Result<Self> {
+        let schedule = schedule.trim();
+        if let Ok(interval) = humantime::parse_duration(schedule) {
+            if interval.is_zero() {
+                return Err(anyhow!("Schedule interval must be greater than zero"));
+            }
+            return Ok(Schedule::Interval(interval));
+        }
+
+        let cron = Cron::from_str(schedule)
+            .map_err(|e| anyhow!("Invalid schedule '{}': {}", schedule, e))?;
+        Ok(Schedule::Cron(Box::new(cron)))
+    }
+
+    // When the entry should run next.
+    // An entry that never ran is due now for intervals and at the next match for cron,
+    // a time in the past means a run was missed (e.g. during a suspend) and is due now.
+    pub fn next_run(
+        &self,
+        last_run: Option<DateTime<Local>>,
+        now: DateTime<Local>,
+    ) -> Result<DateTime<Local>> {
+        match self {
+            Schedule::Interval(interval) => match last_run {
+                Some(last_run) => Ok(last_run + chrono::Duration::from_std(*interval)?),
+                None => Ok(now),
+            },
+            Schedule::Cron(cron) => cron
+                .find_next_occurrence(&last_run.unwrap_or(now), false)
+                .map_err(|e| anyhow!("Failed to find next run for '{}': {}", cron, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod schedule_test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_schedule() {
+        assert!(matches!(
+            Schedule::parse("10m").unwrap(),
+            Schedule::Interval(interval) if interval == Duration::from_secs(600)
+        ));
+        assert!(matches!(
+            Schedule::parse("1h 30m").unwrap(),
+            Schedule::Interval(interval) if interval == Duration::from_secs(5400)
+        ));
+        assert!(matches!(
+            Schedule::parse("0 3 * * *").unwrap(),
+            Schedule::Cron(_)
+        ));
+        assert!(Schedule::parse("0s").is_err());
+        assert!(Schedule::parse("every tuesday").is_err());
+    }
+
+    #[test]
+    fn test_next_run() -> Result<()> {
+        let now = Local.with_ymd_and_hms(2025, 3, 10, 12, 0, 0).unwrap();
+
+        let interval = Schedule::parse("10m")?;
+        assert_eq!(interval.next_run(None, now)?, now);
+        let last_run = Local.with_ymd_and_hms(2025, 3, 10, 11, 55, 0).unwrap();
+        assert_eq!(
+            interval.next_run(Some(last_run), now)?,
+            Local.with_ymd_and_hms(2025, 3, 10, 12, 5, 0).unwrap()
+        );
+
+        let nightly = Schedule::parse("0 3 * * *")?;
+        assert_eq!(
+            nightly.next_run(None, now)?,
+            Local.with_ymd_and_hms(2025, 3, 11, 3, 0, 0).unwrap()
+        );
+        // last ran two days ago, so last night's run was missed and is overdue
+        let last_run = Local.with_ymd_and_hms(2025, 3, 8, 3, 0, 0).unwrap();
+        assert!(nightly.next_run(Some(last_run), now)? <= now);
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use home::home_dir;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::operations::sys_ops;
+
+const SERVICE_FILE: &str = "cl_sync.service";
+const TIMER_FILE: &str = "cl_sync.timer";
+
+// How the generated systemd unit runs cl_sync
+// Timer: `--sync` on a systemd calendar spec (DAILY, hourly, *-*-* 03:00:00)
+// Watch: long running `watch` that syncs on file changes
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServiceMode {
+    Timer(String),
+    Watch,
+}
+
+// Contents of the unit files, the timer is only set in timer mode
+#[derive(Debug)]
+pub struct ServiceUnits {
+    pub service: String,
+    pub timer: Option<String>,
+}
+
+// ~/.config/systemd/user
+pub fn systemd_user_dir() -> PathBuf {
+    let home_path = home_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
+    home_path.join(".config/systemd/user")
+}
+
+pub fn render_units(mode: &ServiceMode, binary: &Path, config_path: &Path) -> ServiceUnits {
+    let binary = binary.to_string_lossy();
+    let config_path = config_path.to_string_lossy();
+
+    match mode {
+        ServiceMode::Timer(on_calendar) => ServiceUnits {
+            service: format!(
+                "[Unit]\n\
+                 Description=cl_sync upload of modified files\n\
+                 After=network-online.target\n\
+                 Wants=network-online.target\n\
+                 \n\
+                 [Service]\n\
+                 Type=oneshot\n\
+                 Environment=\"CL_SYNC_CONFIG={config_path}\"\n\
+                 ExecStart=\"{binary}\" --sync --nointe\n"
+            ),
+            timer: Some(format!(
+                "[Unit]\n\
+                 Description=Run cl_sync on a schedule\n\
+                 \n\
+                 [Timer]\n\
+                 OnCalendar={on_calendar}\n\
+                 Persistent=true\n\
+                 \n\
+                 [Install]\n\
+                 WantedBy=timers.target\n"
+            )),
+        },
+        ServiceMode::Watch => ServiceUnits {
+            service: format!(
+                "[Unit]\n\
+                 Description=cl_sync watch mode\n\
+                 After=network-online.target\n\
+                 Wants=network-online.target\n\
+                 \n\
+                 [Service]\n\
+                 Type=simple\n\
+                 Environment=\"CL_SYNC_CONFIG={config_path}\"\n\
+                 ExecStart=\"{binary}\" --nointe watch\n\
+                 Restart=on-failure\n\
+                 RestartSec=30\n\
+                 \n\
+                 [Install]\n\
+                 WantedBy=default.target\n"
+            ),
+            timer: None,
+        },
+    }
+}
+
+// Write the unit files into unit_dir, a timer left over from
+// a previous timer install is removed when switching to watch mode
+pub async fn write_units(units: &ServiceUnits, unit_dir: &Path) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(unit_dir)
+        .await
+        .with_context(|| format!("Failed to create {}", unit_dir.display()))?;
+
+    let mut written = vec![];
+    let service_path = unit_dir.join(SERVICE_FILE);
+    fs::write(&service_path, &units.service)
+        .await
+        .with_context(|| format!("Failed to write {}", service_path.display()))?;
+    written.push(service_path);
+
+    let timer_path = unit_dir.join(TIMER_FILE);
+    match &units.timer {
+        Some(timer) => {
+            fs::write(&timer_path, timer)
+                .await
+                .with_context(|| format!("Failed to write {}", timer_path.display()))?;
+            written.push(timer_path);
+        }
+        None => {
+            if sys_ops::is_file(timer_path.clone()).await? {
+                fs::remove_file(&timer_path).await?;
+            }
+        }
+    }
+    Ok(written)
+}
+
+// Remove the unit files from unit_dir, returns the files that were removed
+pub async fn remove_units(unit_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    for file in [SERVICE_FILE, TIMER_FILE] {
+        let path = unit_dir.join(file);
+        if sys_ops::is_file(path.clone()).await? {
+            fs::remove_file(&path)
+                .await
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+pub async fn install_service(mode: ServiceMode) -> Result<()> {
+    let binary = std::env::current_exe().context("Failed to find the cl_sync binary")?;
+    let units = render_units(&mode, &binary, &sys_ops::toml_file_path());
+
+    for path in write_units(&units, &systemd_user_dir()).await? {
+        println!("Wrote {}", path.display());
+    }
+
+    let unit = match mode {
+        ServiceMode::Timer(_) => TIMER_FILE,
+        ServiceMode::Watch => SERVICE_FILE,
+    };
+    println!(
+        "Enable it with:\n  systemctl --user daemon-reload\n  systemctl --user enable --now {unit}"
+    );
+    Ok(())
+}
+
+pub async fn uninstall_service() -> Result<()> {
+    let removed = remove_units(&systemd_user_dir()).await?;
+    if removed.is_empty() {
+        println!("No cl_sync units installed.");
+        return Ok(());
+    }
+    for path in removed {
+        println!("Removed {}", path.display());
+    }
+    println!(
+        "Stop running units with:\n  systemctl --user disable --now {TIMER_FILE} {SERVICE_FILE}\n  systemctl --user daemon-reload"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod service_test {
+    use super::*;
+
+    fn temp_unit_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cl_sync_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_render_timer_units() {
+        let units = render_units(
+            &ServiceMode::Timer("DAILY".to_string()),
+            Path::new("/usr/bin/cl_sync"),
+            Path::new("/home/user/.config/cl_sync/upload.toml"),
+        );
+        assert!(units
+            .service
+            .contains("ExecStart=\"/usr/bin/cl_sync\" --sync --nointe"));
+        assert!(units
+            .service
+            .contains("CL_SYNC_CONFIG=/home/user/.config/cl_sync/upload.toml"));
+        assert!(units.timer.unwrap().contains("OnCalendar=DAILY"));
+    }
+
+    #[tokio::test]
+    async fn test_write_and_remove_units() -> Result<()> {
+        let unit_dir = temp_unit_dir("units");
+        let binary = Path::new("/usr/bin/cl_sync");
+        let config = Path::new("/tmp/upload.toml");
+
+        let timer = render_units(&ServiceMode::Timer("hourly".to_string()), binary, config);
+        let written = write_units(&timer, &unit_dir).await?;
+        assert_eq!(written.len(), 2);
+
+        // switching to watch mode drops the timer
+        let watch = render_units(&ServiceMode::Watch, binary, config);
+        let written = write_units(&watch, &unit_dir).await?;
+        assert_eq!(written, vec![unit_dir.join(SERVICE_FILE)]);
+        assert!(!unit_dir.join(TIMER_FILE).exists());
+        let service = fs::read_to_string(unit_dir.join(SERVICE_FILE)).await?;
+        assert!(service.contains("--nointe watch"));
+
+        let removed = remove_units(&unit_dir).await?;
+        assert_eq!(removed, vec![unit_dir.join(SERVICE_FILE)]);
+        assert!(remove_units(&unit_dir).await?.is_empty());
+
+        fs::remove_dir_all(&unit_dir).await?;
+        Ok(())
+    }
+}
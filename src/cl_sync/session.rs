@@ -0,0 +1,295 @@
+use anyhow::Result;
+use hashbrown::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, OnceCell, OwnedMutexGuard};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::error::{DaemonError, RcError, TomlError};
+use crate::operations::job_tracker::JobTracker;
+use crate::operations::rc::RcClient;
+use crate::operations::rclone::{self, RcloneServer, RC_PORT};
+use crate::operations::{sys_ops, toml};
+
+// How long the daemon gets to answer on its port after being started
+const DAEMON_START_TIMEOUT_SECS: u64 = 30;
+
+// State shared by every entry synced during one run:
+// the rclone daemon and the client talking to it, the remotes mounted
+// so far, the jobs being waited for and when the run has to end.
+// Cloning is cheap so it can be handed to spawned tasks.
+#[derive(Clone, Default)]
+pub struct SyncSession {
+    pub rclone_server: Arc<Mutex<Option<RcloneServer>>>,
+    pub client: RcClient,
+    pub mounted_remotes: Arc<Mutex<Vec<String>>>,
+    // one per mount point, held while it is checked and mounted
+    mount_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    pub jobs: JobTracker,
+    pub deadline: Option<Instant>,
+    // applied to the daemon once it is started
+    pub bwlimit: Option<String>,
+    // options the daemon knows, asked for once
+    rclone_option_names: Arc<OnceCell<HashMap<String, Vec<String>>>>,
+}
+
+impl SyncSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+            ..Self::default()
+        }
+    }
+
+    // Talk to the daemon client calls, its jobs included
+    pub fn with_client(mut self, client: RcClient) -> Self {
+        self.jobs = JobTracker::new(client.clone());
+        self.client = client;
+        self
+    }
+
+    pub fn with_bwlimit(mut self, bwlimit: Option<String>) -> Self {
+        self.bwlimit = bwlimit;
+        self
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // When the jobs of an entry starting now have to be done:
+    // its own timeout, cut short by the end of the run
+    pub fn entry_deadline(
+        &self,
+        key: &str,
+        to_up: &toml::TomlUpload,
+    ) -> Result<Option<Instant>, TomlError> {
+        let entry_deadline = match &to_up.timeout {
+            Some(timeout) => {
+                let section = format!("upload.{}", key);
+                let timeout = toml::parse_duration_setting(&section, "timeout", timeout)?;
+                Some(Instant::now() + timeout)
+            }
+            None => None,
+        };
+        Ok(match (entry_deadline, self.deadline) {
+            (Some(entry), Some(run)) => Some(entry.min(run)),
+            (entry, run) => entry.or(run),
+        })
+    }
+
+    // Start the rclone daemon unless it is already running for this run
+    pub async fn start_rclone_server(&self) -> Result<()> {
+        let mut rclone_server = self.rclone_server.lock().await;
+        let started = rclone_server.is_none();
+        if started {
+            *rclone_server = Some(RcloneServer::start().await?);
+        } else {
+            debug!("server all ready started.")
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(DAEMON_START_TIMEOUT_SECS);
+        while !RcloneServer::is_running().await {
+            if Instant::now() >= deadline {
+                return Err(DaemonError::NotReachable {
+                    port: RC_PORT,
+                    secs: DAEMON_START_TIMEOUT_SECS,
+                }
+                .into());
+            }
+            println!("Waiting for rclone to start...");
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+
+        if let (true, Some(rate)) = (started, &self.bwlimit) {
+            let rate = rclone::bwlimit(&self.client, Some(rate)).await?;
+            println!("Bandwidth limit: {}", rate);
+        }
+        Ok(())
+    }
+
+    // Option names by block, see rclone::option_names
+    pub async fn rclone_option_names(
+        &self,
+    ) -> std::result::Result<&HashMap<String, Vec<String>>, RcError> {
+        self.rclone_option_names
+            .get_or_try_init(|| rclone::option_names(&self.client))
+            .await
+    }
+
+    // Held by whoever mounts dir, so entries synced at the same time
+    // don't both find it unmounted and mount it twice
+    pub async fn lock_mount_point(&self, dir: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .mount_locks
+            .lock()
+            .await
+            .entry(dir.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    pub async fn add_mounted_remote(&self, dir: &str) {
+        let mut mounted_remotes = self.mounted_remotes.lock().await;
+        if !mounted_remotes.iter().any(|mounted| mounted == dir) {
+            mounted_remotes.push(dir.to_string());
+        }
+    }
+
+    pub async fn is_mounted(&self, dir: &str) -> bool {
+        self.mounted_remotes
+            .lock()
+            .await
+            .iter()
+            .any(|mounted| mounted == dir)
+    }
+
+    // Dismount every remote and stop rclone, a failed dismount
+    // doesn't keep the others mounted or rclone running
+    pub async fn finish(&self) -> Result<()> {
+        let mounted_remotes: Vec<String> = self.mounted_remotes.lock().await.drain(..).collect();
+        let result = dismount(&self.client, &mounted_remotes).await;
+
+        if let Some(mut server) = self.rclone_server.lock().await.take() {
+            server.stop().await;
+        }
+        result
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Dismount {
+    // every mount of the daemon is ours
+    All,
+    Each(Vec<String>),
+}
+
+// Which of our remotes are still mounted according to the daemon.
+// Remotes that went away on their own are left out.
+fn plan_dismount(mounted_remotes: &[String], active: &[String]) -> Dismount {
+    let still_mounted: Vec<String> = mounted_remotes
+        .iter()
+        .filter(|remote| active.contains(remote))
+        .cloned()
+        .collect();
+    if !still_mounted.is_empty() && active.iter().all(|mount| still_mounted.contains(mount)) {
+        return Dismount::All;
+    }
+    Dismount::Each(still_mounted)
+}
+
+// Dismount through the daemon that mounted the remotes,
+// fusermount is only used once the daemon is gone
+async fn dismount(client: &RcClient, mounted_remotes: &[String]) -> Result<()> {
+    if mounted_remotes.is_empty() {
+        return Ok(());
+    }
+    if !RcloneServer::is_running().await {
+        return fusermount_all(mounted_remotes).await;
+    }
+
+    let plan = match rclone::list_mounts(client).await {
+        Ok(active) => plan_dismount(mounted_remotes, &active),
+        Err(e) => {
+            debug!("mount/listmounts failed: {:?}", e);
+            Dismount::Each(mounted_remotes.to_vec())
+        }
+    };
+    let remotes = match plan {
+        Dismount::All => match rclone::unmount_all(client).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                debug!("mount/unmountall failed: {:?}", e);
+                mounted_remotes.to_vec()
+            }
+        },
+        Dismount::Each(remotes) => remotes,
+    };
+
+    let mut first_error = None;
+    for remote in remotes {
+        let result = match rclone::unmount(client, &remote).await {
+            Err(RcError::Request { .. }) => fusermount_all(std::slice::from_ref(&remote)).await,
+            result => result.map_err(anyhow::Error::from),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to dismount {}: {:?}", remote, e);
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+async fn fusermount_all(mounted_remotes: &[String]) -> Result<()> {
+    let mut first_error = None;
+    for remote in mounted_remotes {
+        let result = match sys_ops::fusermount(remote).await {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(anyhow::anyhow!(
+                "fusermount -u {} failed: {}",
+                remote,
+                status
+            )),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to dismount {}: {:?}", remote, e);
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod session_test {
+    use super::*;
+
+    #[test]
+    fn test_entry_deadline() -> anyhow::Result<()> {
+        let mut to_up = toml::TomlUpload {
+            timeout: Some("1h".to_string()),
+            ..Default::default()
+        };
+
+        // the end of the run cuts the entry's own timeout short
+        let session = SyncSession::with_timeout(Duration::from_secs(60));
+        assert_eq!(session.entry_deadline("vault", &to_up)?, session.deadline);
+
+        let session = SyncSession::new();
+        let deadline = session.entry_deadline("vault", &to_up)?.unwrap();
+        assert!(deadline > Instant::now() + Duration::from_secs(3500));
+
+        to_up.timeout = Some("soon".to_string());
+        assert!(session.entry_deadline("vault", &to_up).is_err());
+        to_up.timeout = None;
+        assert_eq!(session.entry_deadline("vault", &to_up)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_dismount() {
+        let ours = vec!["/home/user/dge".to_string(), "/home/user/dg".to_string()];
+        assert_eq!(plan_dismount(&ours, &ours), Dismount::All);
+
+        // dg went away on its own, another mount of the daemon isn't ours
+        let active = vec!["/home/user/dge".to_string(), "/mnt/other".to_string()];
+        assert_eq!(
+            plan_dismount(&ours, &active),
+            Dismount::Each(vec!["/home/user/dge".to_string()])
+        );
+        assert_eq!(plan_dismount(&ours, &[]), Dismount::Each(vec![]));
+    }
+}
@@ -0,0 +1,216 @@
+use chrono::{DateTime, Local, NaiveDateTime};
+use std::collections::HashSet;
+
+use crate::error::{RcError, RcloneError};
+use crate::operations::rc::RcClient;
+use crate::operations::{rclone, toml};
+
+// Name of the dir of a snapshot, sorts by time
+const NAME_FORMAT: &str = "%Y-%m-%d_%H%M%S";
+
+// Dir the snapshot of an upload started at time goes to
+pub fn name(time: DateTime<Local>) -> String {
+    time.format(NAME_FORMAT).to_string()
+}
+
+// Where an upload to a cloud goes, the dir of the snapshot
+// inside the destination of the entry in snapshot mode
+pub fn destination(
+    to_up: &toml::TomlUpload,
+    provider: &toml::CloudProviders,
+    snapshot: &str,
+) -> toml::Destination {
+    let destination = toml::Destination::resolve(to_up, provider);
+    match to_up.mode(&provider.cloud_name) {
+        toml::UploadMode::Snapshot => destination.subdir(snapshot),
+        _ => destination,
+    }
+}
+
+// Purge the snapshots in destination the retention of the entry doesn't
+// keep. Dirs that aren't named like a snapshot are never touched.
+pub async fn prune(
+    client: &RcClient,
+    destination: &toml::Destination,
+    retention: &toml::Retention,
+) -> Result<(), RcError> {
+    if retention.keeps_all() {
+        return Ok(());
+    }
+    let snapshots: Vec<NaiveDateTime> =
+        rclone::list_dirs(client, &destination.fs, &destination.path)
+            .await?
+            .iter()
+            .filter_map(|dir| NaiveDateTime::parse_from_str(&dir.name, NAME_FORMAT).ok())
+            .collect();
+
+    for snapshot in expired(snapshots, retention) {
+        let path = destination.file(&snapshot.format(NAME_FORMAT).to_string());
+        println!("Purging old snapshot {}{}", destination.fs, path);
+        rclone::purge(client, &destination.fs, &path).await?;
+    }
+    Ok(())
+}
+
+// Remove the dir of a snapshot whose upload failed, a partial upload must
+// not count as a snapshot when pruning. There is nothing to remove when
+// the upload failed before creating it.
+pub async fn discard(client: &RcClient, destination: &toml::Destination) -> Result<(), RcError> {
+    match rclone::purge(client, &destination.fs, &destination.path).await {
+        Err(RcError::Status {
+            error: RcloneError::NotFound(_),
+            ..
+        }) => Ok(()),
+        result => result,
+    }
+}
+
+// Snapshots none of the rules keeps
+fn expired(mut snapshots: Vec<NaiveDateTime>, retention: &toml::Retention) -> Vec<NaiveDateTime> {
+    if retention.keeps_all() {
+        return vec![];
+    }
+    // newest first
+    snapshots.sort_by(|a, b| b.cmp(a));
+
+    let mut keep: HashSet<NaiveDateTime> = snapshots.iter().take(1).copied().collect();
+    if let Some(last) = retention.keep_last {
+        keep.extend(snapshots.iter().take(last as usize));
+    }
+    keep_per_period(&snapshots, retention.keep_daily, "%Y-%m-%d", &mut keep);
+    keep_per_period(&snapshots, retention.keep_weekly, "%G-W%V", &mut keep);
+    keep_per_period(&snapshots, retention.keep_monthly, "%Y-%m", &mut keep);
+
+    snapshots
+        .into_iter()
+        .filter(|snapshot| !keep.contains(snapshot))
+        .collect()
+}
+
+// Keep the newest snapshot of each of the last count periods that have one,
+// a period is the day, week or month period_format formats the time as
+fn keep_per_period(
+    snapshots: &[NaiveDateTime],
+    count: Option<u32>,
+    period_format: &str,
+    keep: &mut HashSet<NaiveDateTime>,
+) {
+    let Some(count) = count else {
+        return;
+    };
+    let mut periods: Vec<String> = vec![];
+    for snapshot in snapshots {
+        let period = snapshot.format(period_format).to_string();
+        if periods.contains(&period) {
+            continue;
+        }
+        if periods.len() == count as usize {
+            break;
+        }
+        periods.push(period);
+        keep.insert(*snapshot);
+    }
+}
+
+#[cfg(test)]
+mod snapshot_test {
+    use super::*;
+    use crate::test_fixtures::{entry, provider, RcStub};
+    use serde_json::json;
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, NAME_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn test_expired() {
+        let snapshots = vec![
+            at("2026-08-30_120000"),
+            at("2026-09-28_120000"),
+            at("2026-10-01_090000"),
+            at("2026-10-01_180000"),
+            at("2026-10-02_120000"),
+            at("2026-10-03_120000"),
+        ];
+
+        let keep_all = toml::Retention::default();
+        assert!(expired(snapshots.clone(), &keep_all).is_empty());
+
+        let last = toml::Retention {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            expired(snapshots.clone(), &last),
+            vec![
+                at("2026-10-01_180000"),
+                at("2026-10-01_090000"),
+                at("2026-09-28_120000"),
+                at("2026-08-30_120000"),
+            ]
+        );
+
+        // the newest of a day, of a month
+        let periods = toml::Retention {
+            keep_daily: Some(3),
+            keep_monthly: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            expired(snapshots.clone(), &periods),
+            vec![at("2026-10-01_090000")]
+        );
+
+        // the newest snapshot is never purged
+        let none = toml::Retention {
+            keep_last: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(expired(snapshots, &none).len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_discard() {
+        let stub = RcStub::start(|_, params| match params["remote"].as_str() {
+            Some("desk/Vault/2026-10-19_031500") => json!({}),
+            Some("desk/Vault/2026-10-19_041500") => {
+                json!({ "error": "directory not found", "status": 500 })
+            }
+            _ => json!({ "error": "permission denied", "status": 500 }),
+        })
+        .await;
+        let client = stub.client();
+        let base =
+            toml::Destination::resolve(&entry("/home/user/vault", &["dge"]), &provider("dge"));
+
+        assert!(discard(&client, &base.subdir("2026-10-19_031500"))
+            .await
+            .is_ok());
+        assert_eq!(
+            stub.params("operations/purge")[0],
+            json!({ "fs": "dge:", "remote": "desk/Vault/2026-10-19_031500" })
+        );
+        // the upload failed before it created the dir
+        assert!(discard(&client, &base.subdir("2026-10-19_041500"))
+            .await
+            .is_ok());
+        assert!(discard(&client, &base.subdir("2026-10-19_051500"))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_destination() {
+        let dge = provider("dge");
+        let mut to_up = entry("/home/user/vault", &["dge"]);
+        assert_eq!(
+            destination(&to_up, &dge, "2026-10-19_031500").remote(),
+            "dge:desk/Vault"
+        );
+        to_up.mode = toml::UploadMode::Snapshot;
+        assert_eq!(
+            destination(&to_up, &dge, "2026-10-19_031500").remote(),
+            "dge:desk/Vault/2026-10-19_031500"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::cl_sync::cache;
+use crate::operations::{sys_ops, toml};
+
+// What cl_sync knows about one upload entry
+// dirty: at least one cloud doesn't have the current local state
+#[derive(Debug, Serialize)]
+pub struct EntryStatus {
+    pub entry: String,
+    pub path: String,
+    pub exists: bool,
+    pub dirty: bool,
+    pub clouds: Vec<CloudStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CloudStatus {
+    pub cloud: String,
+    pub last_synced: Option<DateTime<Local>>,
+    pub seconds_since_sync: Option<i64>,
+    pub behind: bool,
+    pub last_error: Option<String>,
+}
+
+pub async fn collect_status(parsed_toml: &toml::TomlParser) -> Result<Vec<EntryStatus>> {
+    let upload_list = match parsed_toml
+        .get_section_from_toml(toml::TomlSection::Upload)
+        .await
+    {
+        Ok(toml::TomlToParse::Upload(dir)) => dir,
+        _ => return Err(anyhow!("Unexpected section type for upload list")),
+    };
+    let cache = cache::load(parsed_toml).await?;
+    let now = Local::now();
+
+    let mut keys: Vec<&String> = upload_list.keys().collect();
+    keys.sort();
+
+    let mut statuses = vec![];
+    for key in keys {
+        let to_up = &upload_list[key];
+        let path = PathBuf::from(&to_up.file_or_dir_path);
+        let exists = sys_ops::is_dir(path.clone()).await? || sys_ops::is_file(path.clone()).await?;
+        let file = cache.get(key).await;
+
+        let behind = match (&file, exists) {
+            // nothing to upload while the source is missing
+            (_, false) => vec![],
+            (None, true) => to_up.clouds(),
+            (Some(file), true) => {
+                let manifest_hash = sys_ops::manifest_hash(&path).await?;
+                let destinations = cache::destinations(parsed_toml, to_up).await;
+                cache::clouds_behind(Some(file), to_up, &manifest_hash, &destinations).await?
+            }
+        };
+
+        let clouds = to_up
+            .clouds()
+            .iter()
+            .map(|cloud| {
+                let state = file.as_ref().and_then(|file| file.clouds.get(cloud));
+                // entries from a cache without per-cloud state only know last_saved
+                let last_synced = match (&file, state) {
+                    (_, Some(state)) => state.last_synced,
+                    (Some(file), None) if file.clouds.is_empty() => Some(file.last_saved),
+                    _ => None,
+                };
+                CloudStatus {
+                    cloud: cloud.to_string(),
+                    last_synced,
+                    seconds_since_sync: last_synced.map(|at| (now - at).num_seconds()),
+                    behind: behind.contains(cloud),
+                    last_error: state.and_then(|state| state.last_error.clone()),
+                }
+            })
+            .collect();
+
+        statuses.push(EntryStatus {
+            entry: key.to_string(),
+            path: to_up.file_or_dir_path.to_string(),
+            exists,
+            dirty: !behind.is_empty(),
+            clouds,
+        });
+    }
+    Ok(statuses)
+}
+
+pub async fn begin_status(parsed_toml: &toml::TomlParser, json: bool) -> Result<()> {
+    let statuses = collect_status(parsed_toml).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+        return Ok(());
+    }
+
+    for status in &statuses {
+        let state = if !status.exists {
+            "missing"
+        } else if status.dirty {
+            "modified"
+        } else {
+            "up to date"
+        };
+        println!("[upload.{}] {} ({})", status.entry, status.path, state);
+
+        for cloud in &status.clouds {
+            let last_sync = match (cloud.last_synced, cloud.seconds_since_sync) {
+                (Some(at), Some(seconds)) => format!(
+                    "last sync {} ({} ago)",
+                    at.format("%Y-%m-%d %H:%M:%S"),
+                    format_since(seconds)
+                ),
+                _ => "never synced".to_string(),
+            };
+            let behind = if cloud.behind { ", behind" } else { "" };
+            print!("  {:<12} {}{}", cloud.cloud, last_sync, behind);
+            if let Some(error) = &cloud.last_error {
+                print!(", last error: {}", error);
+            }
+            println!();
+        }
+    }
+    Ok(())
+}
+
+// 3725 -> "1h 2m", durations under a minute keep their seconds
+fn format_since(seconds: i64) -> String {
+    let seconds = seconds.max(0) as u64;
+    let rounded = if seconds >= 60 {
+        seconds - seconds % 60
+    } else {
+        seconds
+    };
+    humantime::format_duration(std::time::Duration::from_secs(rounded)).to_string()
+}
+
+#[cfg(test)]
+mod status_test {
+    use super::*;
+    use crate::operations::cl_sync_cache::{CloudSyncState, ToUpload};
+    use crate::test_fixtures::{entry, parser, write_cache};
+    use hashbrown::HashMap;
+
+    #[tokio::test]
+    async fn test_collect_status() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let vault = tmp.path().join("vault");
+        std::fs::create_dir(&vault)?;
+        std::fs::write(vault.join("notes.md"), "notes")?;
+        let manifest_hash = sys_ops::manifest_hash(&vault).await?;
+        let synced_at = Local::now() - chrono::Duration::hours(1);
+
+        let mut clouds = HashMap::new();
+        clouds.insert(
+            "synced".to_string(),
+            CloudSyncState {
+                last_synced: Some(synced_at),
+                manifest_hash: Some(manifest_hash),
+                ..Default::default()
+            },
+        );
+        clouds.insert(
+            "failed".to_string(),
+            CloudSyncState {
+                last_attempt: Some(synced_at),
+                last_error: Some("quota exceeded".to_string()),
+                ..Default::default()
+            },
+        );
+        let cache_path = tmp.path().join("cache.bin");
+        let record = ToUpload {
+            file_path: vault.to_string_lossy().to_string(),
+            last_saved: synced_at,
+            clouds,
+        };
+        write_cache(&cache_path, &[("vault", record)]).await;
+
+        let vault_entry = entry(&vault.to_string_lossy(), &["synced", "failed", "never"]);
+        let missing = tmp.path().join("missing");
+        let missing_entry = entry(&missing.to_string_lossy(), &["synced"]);
+        let parsed_toml = parser(
+            &[("vault", vault_entry), ("missing", missing_entry)],
+            &cache_path,
+        );
+
+        let statuses = collect_status(&parsed_toml).await?;
+        assert_eq!(statuses.len(), 2);
+
+        // nothing to upload while the source is missing
+        let missing = &statuses[0];
+        assert_eq!(missing.entry, "missing");
+        assert!(!missing.exists);
+        assert!(!missing.dirty);
+        assert_eq!(missing.clouds[0].last_synced, None);
+
+        let vault = &statuses[1];
+        assert!(vault.exists);
+        assert!(vault.dirty);
+        let synced = &vault.clouds[0];
+        assert_eq!(synced.cloud, "synced");
+        assert_eq!(synced.last_synced, Some(synced_at));
+        assert!(synced.seconds_since_sync.unwrap() >= 3600);
+        assert!(!synced.behind);
+        let failed = &vault.clouds[1];
+        assert_eq!(failed.last_synced, None);
+        assert!(failed.behind);
+        assert_eq!(failed.last_error.as_deref(), Some("quota exceeded"));
+        let never = &vault.clouds[2];
+        assert_eq!(never.cloud, "never");
+        assert_eq!(never.last_synced, None);
+        assert!(never.behind);
+        assert_eq!(never.last_error, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_since() {
+        assert_eq!(format_since(42), "42s");
+        assert_eq!(format_since(3725), "1h 2m");
+        assert_eq!(format_since(-5), "0s");
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::Result;
+
+use crate::error::ClSyncError;
+use crate::operations::toml;
+
+// What happened to an entry on one of its clouds
+#[derive(Debug)]
+pub enum CloudOutcome {
+    Synced,
+    UpToDate,
+    // the entry was not attempted, e.g. its source is missing
+    Skipped(String),
+    Failed(ClSyncError),
+}
+
+// Outcome of an entry per cloud
+pub type EntryOutcome = Vec<(String, CloudOutcome)>;
+
+#[derive(Debug, PartialEq)]
+enum RowResult {
+    Synced,
+    UpToDate,
+    Skipped(String),
+    Failed(String),
+    TimedOut(String),
+    NotAttempted,
+}
+
+#[derive(Debug)]
+struct SummaryRow {
+    entry: String,
+    cloud: String,
+    result: RowResult,
+}
+
+// Entry x cloud results of a whole run, printed as a table at the end
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    rows: Vec<SummaryRow>,
+    // every failure in the order it happened, the first decides the exit code
+    errors: Vec<anyhow::Error>,
+}
+
+impl SyncSummary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, entry: &str, outcome: EntryOutcome) {
+        for (cloud, outcome) in outcome {
+            let result = match outcome {
+                CloudOutcome::Synced => RowResult::Synced,
+                CloudOutcome::UpToDate => RowResult::UpToDate,
+                CloudOutcome::Skipped(reason) => RowResult::Skipped(reason),
+                CloudOutcome::Failed(e) if e.is_timeout() => {
+                    let result = RowResult::TimedOut(e.to_string());
+                    self.errors.push(e.into());
+                    result
+                }
+                CloudOutcome::Failed(e) => {
+                    let result = RowResult::Failed(e.to_string());
+                    self.errors.push(e.into());
+                    result
+                }
+            };
+            self.push(entry, &cloud, result);
+        }
+    }
+
+    // The entry failed before any of its clouds could be tried
+    pub fn record_error(&mut self, entry: &str, to_up: &toml::TomlUpload, error: anyhow::Error) {
+        let timed_out = error
+            .downcast_ref::<ClSyncError>()
+            .is_some_and(ClSyncError::is_timeout);
+        for cloud in &to_up.clouds() {
+            let result = if timed_out {
+                RowResult::TimedOut(format!("{:#}", error))
+            } else {
+                RowResult::Failed(format!("{:#}", error))
+            };
+            self.push(entry, cloud, result);
+        }
+        self.errors.push(error);
+    }
+
+    // Left out by --fail-fast after an earlier entry failed
+    pub fn record_not_attempted(&mut self, entry: &str, to_up: &toml::TomlUpload) {
+        for cloud in &to_up.clouds() {
+            self.push(entry, cloud, RowResult::NotAttempted);
+        }
+    }
+
+    pub fn has_failures(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    fn push(&mut self, entry: &str, cloud: &str, result: RowResult) {
+        self.rows.push(SummaryRow {
+            entry: entry.to_string(),
+            cloud: cloud.to_string(),
+            result,
+        });
+    }
+
+    fn count(&self, wanted: fn(&RowResult) -> bool) -> usize {
+        self.rows.iter().filter(|row| wanted(&row.result)).count()
+    }
+
+    pub fn print(&self) {
+        if self.rows.is_empty() {
+            return;
+        }
+        let entry_width = self
+            .rows
+            .iter()
+            .map(|row| row.entry.len())
+            .max()
+            .unwrap_or(0)
+            .max("ENTRY".len());
+        let cloud_width = self
+            .rows
+            .iter()
+            .map(|row| row.cloud.len())
+            .max()
+            .unwrap_or(0)
+            .max("CLOUD".len());
+
+        println!("\nSummary:");
+        println!(
+            "  {:<entry_width$}  {:<cloud_width$}  RESULT",
+            "ENTRY", "CLOUD",
+        );
+        for row in &self.rows {
+            let result = match &row.result {
+                RowResult::Synced => "synced".to_string(),
+                RowResult::UpToDate => "up to date".to_string(),
+                RowResult::Skipped(reason) => format!("skipped: {}", reason),
+                RowResult::Failed(error) => format!("failed: {}", error),
+                RowResult::TimedOut(error) => format!("timed out: {}", error),
+                RowResult::NotAttempted => "not attempted".to_string(),
+            };
+            println!(
+                "  {:<entry_width$}  {:<cloud_width$}  {}",
+                row.entry, row.cloud, result,
+            );
+        }
+        println!(
+            "{} synced, {} up to date, {} failed",
+            self.count(|result| *result == RowResult::Synced),
+            self.count(|result| *result == RowResult::UpToDate),
+            self.count(is_failure)
+        );
+    }
+
+    // Ok when nothing failed. When every upload that was tried failed the
+    // first error is returned so the exit code names its category,
+    // when some uploads went through it is a partial failure.
+    pub fn into_result(self) -> Result<()> {
+        let failed = self.count(is_failure);
+        let synced = self.count(|result| *result == RowResult::Synced);
+        let Some(first_error) = self.errors.into_iter().next() else {
+            return Ok(());
+        };
+        if synced == 0 {
+            return Err(first_error.context(format!("All {} uploads failed", failed)));
+        }
+        Err(ClSyncError::PartialFailure {
+            failed,
+            attempted: failed + synced,
+        }
+        .into())
+    }
+}
+
+fn is_failure(result: &RowResult) -> bool {
+    matches!(result, RowResult::Failed(_) | RowResult::TimedOut(_))
+}
+
+#[cfg(test)]
+mod summary_test {
+    use super::*;
+    use crate::error::{self, RcloneError};
+
+    fn job_failed() -> CloudOutcome {
+        CloudOutcome::Failed(ClSyncError::Job {
+            job_id: 1,
+            error: RcloneError::from_message("quota exceeded"),
+        })
+    }
+
+    #[test]
+    fn test_partial_and_total_failure() {
+        let mut summary = SyncSummary::new();
+        summary.record(
+            "vault",
+            vec![
+                ("dge".to_string(), CloudOutcome::Synced),
+                ("gdrive".to_string(), job_failed()),
+            ],
+        );
+        let err = summary.into_result().unwrap_err();
+        assert_eq!(error::exit_code(&err), error::EXIT_PARTIAL);
+
+        let mut summary = SyncSummary::new();
+        summary.record(
+            "vault",
+            vec![
+                ("dge".to_string(), CloudOutcome::UpToDate),
+                ("gdrive".to_string(), job_failed()),
+            ],
+        );
+        let err = summary.into_result().unwrap_err();
+        assert_eq!(error::exit_code(&err), error::EXIT_JOB);
+
+        let mut summary = SyncSummary::new();
+        summary.record("vault", vec![("dge".to_string(), CloudOutcome::UpToDate)]);
+        assert!(summary.into_result().is_ok());
+    }
+}
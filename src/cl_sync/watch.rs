@@ -0,0 +1,213 @@
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use hashbrown::HashMap;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::cl_sync;
+use crate::operations::job_tracker::JobEvent;
+use crate::operations::{sys_ops, toml};
+
+// Keeps one rclone daemon alive and syncs an upload entry
+// once its files stopped changing for the quiet period
+pub async fn begin_watch(parsed_toml: &toml::TomlParser, quiet_period: Option<u64>) -> Result<()> {
+    let upload_list = match parsed_toml
+        .get_section_from_toml(toml::TomlSection::Upload)
+        .await
+    {
+        Ok(toml::TomlToParse::Upload(dir)) => dir,
+        _ => return Err(anyhow!("Unexpected section type for upload list")),
+    };
+
+    let quiet_period = match quiet_period {
+        Some(secs) => secs,
+        None => match parsed_toml
+            .get_section_from_toml(toml::TomlSection::Watch)
+            .await
+        {
+            Ok(toml::TomlToParse::Watch(watch)) => watch.quiet_period_secs,
+            _ => return Err(anyhow!("Unexpected section type for watch")),
+        },
+    };
+    let quiet_period = Duration::from_secs(quiet_period);
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+        Ok(event) => {
+            let _ = tx.send(event);
+        }
+        Err(e) => eprintln!("Watch error: {:?}", e),
+    })?;
+
+    let watched = watched_paths(&upload_list).await;
+    for (key, path) in &watched {
+        let to_up = &upload_list[key];
+        if sys_ops::is_dir(path.clone()).await? {
+            watcher.watch(path, RecursiveMode::Recursive)?;
+        } else if sys_ops::is_file(path.clone()).await? {
+            // Editors usually replace a file instead of writing to it,
+            // so watch the parent dir to keep seeing it after a save
+            let parent = path.parent().unwrap_or(Path::new("/"));
+            watcher.watch(parent, RecursiveMode::NonRecursive)?;
+        } else {
+            eprintln!(
+                "Skipping: {} is neither a directory nor a file",
+                to_up.file_or_dir_path
+            );
+            continue;
+        }
+        println!("Watching [upload.{}]: {}", key, to_up.file_or_dir_path);
+    }
+
+    let bwlimit = cl_sync::sync_config(parsed_toml).await?.bwlimit;
+    let session = cl_sync::SyncSession::new().with_bwlimit(bwlimit);
+    let mut completions = Box::pin(session.jobs.completions());
+    // entry key -> when it is due to be synced
+    let mut pending: HashMap<String, Instant> = HashMap::new();
+
+    loop {
+        let next_due = pending.values().min().copied();
+        tokio::select! {
+            event = rx.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+                for key in affected_entries(&watched, &event.paths) {
+                    debug!("Change in [upload.{}]: {:?}", key, event.paths);
+                    pending.insert(key, Instant::now() + quiet_period);
+                }
+            }
+            _ = sleep_until_due(next_due) => {
+                let now = Instant::now();
+                let due: Vec<String> = pending
+                    .iter()
+                    .filter(|(_, at)| **at <= now)
+                    .map(|(key, _)| key.to_string())
+                    .collect();
+
+                for key in due {
+                    pending.remove(&key);
+                    let Some(to_up) = upload_list.get(&key) else {
+                        continue;
+                    };
+                    println!("Syncing [upload.{}]", key);
+                    let result = cl_sync::sync_if_modified(parsed_toml, &key, to_up, &session)
+                        .await
+                        .and_then(|outcome| cl_sync::entry_result(to_up, outcome));
+                    if let Err(e) = result {
+                        eprintln!("Failed to sync [upload.{}]: {:?}", key, e);
+                    }
+                }
+            }
+            Some(event) = completions.next() => log_job_event(&event),
+            _ = tokio::signal::ctrl_c() => {
+                println!("Stopping watch.");
+                break;
+            }
+        }
+    }
+
+    drop(watcher);
+    // Dismount and stop rclone when done
+    session.finish().await?;
+    Ok(())
+}
+
+// Uploads are tagged with the key of their entry, mounts aren't tagged
+fn log_job_event(event: &JobEvent) {
+    let job = match &event.status.group {
+        Some(key) => format!("rclone job {} of [upload.{}]", event.job_id, key),
+        None => format!("rclone job {}", event.job_id),
+    };
+    match (&event.status.error, event.status.success) {
+        (_, true) => println!("{} finished", job),
+        (Some(e), false) => eprintln!("{} failed: {}", job, e),
+        (None, false) => eprintln!("{} failed", job),
+    }
+}
+
+async fn sleep_until_due(due: Option<Instant>) {
+    match due {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Path of every entry with its symlinks resolved, the one that gets
+// watched and that the paths of its events start with
+async fn watched_paths(
+    upload_list: &HashMap<String, toml::TomlUpload>,
+) -> HashMap<String, PathBuf> {
+    let mut watched = HashMap::new();
+    for (key, to_up) in upload_list {
+        let path = sys_ops::canonical_path(&to_up.file_or_dir_path).await;
+        watched.insert(key.to_string(), PathBuf::from(path));
+    }
+    watched
+}
+
+// Map the paths of a file system event back to the upload entries they belong to
+fn affected_entries(watched: &HashMap<String, PathBuf>, paths: &[PathBuf]) -> Vec<String> {
+    watched
+        .iter()
+        .filter(|(_, entry_path)| paths.iter().any(|path| path.starts_with(entry_path)))
+        .map(|(key, _)| key.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod watch_test {
+    use super::*;
+    use crate::test_fixtures::entry;
+
+    #[tokio::test]
+    async fn test_affected_entries() {
+        let mut upload_list = HashMap::new();
+        upload_list.insert("vault".to_string(), entry("/home/user/vault/", &["dge"]));
+        upload_list.insert("db".to_string(), entry("/home/user/pw.kdbx", &["dge"]));
+        let watched = watched_paths(&upload_list).await;
+
+        let changed = affected_entries(
+            &watched,
+            &[PathBuf::from("/home/user/vault/notes/today.md")],
+        );
+        assert_eq!(changed, vec!["vault".to_string()]);
+
+        let changed = affected_entries(&watched, &[PathBuf::from("/home/user/pw.kdbx")]);
+        assert_eq!(changed, vec!["db".to_string()]);
+
+        let changed = affected_entries(&watched, &[PathBuf::from("/home/user/other.txt")]);
+        assert!(changed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_affected_entries_through_a_symlink() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let real = tmp.path().join("real");
+        std::fs::create_dir_all(real.join("notes"))?;
+        let link = tmp.path().join("vault");
+        std::os::unix::fs::symlink(&real, &link)?;
+
+        let mut upload_list = HashMap::new();
+        upload_list.insert(
+            "vault".to_string(),
+            entry(&link.to_string_lossy(), &["dge"]),
+        );
+        let watched = watched_paths(&upload_list).await;
+
+        // events name the real path of what is watched
+        let event_path = real.canonicalize()?.join("notes/today.md");
+        assert_eq!(
+            affected_entries(&watched, &[event_path]),
+            vec!["vault".to_string()]
+        );
+        Ok(())
+    }
+}
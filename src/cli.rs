@@ -6,6 +6,21 @@ use std::path::PathBuf;
 pub fn build_cli() -> Command {
     Command::new("cl_sync")
         .about("Upload and synchronize files to multiple storage cloud providers.")
+        .after_help(
+            "Exit codes:\n  \
+             1  other error\n  \
+             2  invalid arguments\n  \
+             3  upload.toml error\n  \
+             4  cache error\n  \
+             5  rclone daemon could not be started\n  \
+             6  rclone remote control request failed\n  \
+             7  rclone job failed\n  \
+             8  mount failed\n  \
+             9  local file system error\n  \
+             10 some uploads failed, the others went through\n  \
+             11 an upload or the whole run timed out\n  \
+             12 a cloud doesn't have enough space left",
+        )
         .arg(
             Arg::new("upload")
                 .long("upload")
@@ -30,6 +45,12 @@ pub fn build_cli() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("Upload only modifie files."),
         )
+        .arg(
+            Arg::new("fail_fast")
+                .long("fail-fast")
+                .action(ArgAction::SetTrue)
+                .help("Stop syncing after the first entry that fails."),
+        )
         .arg(
             Arg::new("debug")
                 .long("debug")
@@ -43,6 +64,20 @@ pub fn build_cli() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("Disbale interactive mode."),
         )
+        .arg(
+            Arg::new("wait")
+                .long("wait")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Wait for another running cl_sync to finish instead of failing."),
+        )
+        .arg(
+            Arg::new("bwlimit")
+                .long("bwlimit")
+                .global(true)
+                .value_name("RATE")
+                .help("Upload limit for this run, e.g. 512k or \"08:00,512k 23:00,off\"; overrides [sync] bwlimit."),
+        )
         .arg(
             Arg::new("generator")
                 .long("generate")
@@ -50,6 +85,107 @@ pub fn build_cli() -> Command {
                 .help("Generate shell completions.")
                 .value_parser(value_parser!(Shell)),
         )
+        .subcommand(
+            Command::new("watch")
+                .about("Watch every upload entry and sync it when it changes.")
+                .arg(
+                    Arg::new("quiet_period")
+                        .long("quiet-period")
+                        .help("Seconds an entry has to stay unchanged before it is synced.")
+                        .value_name("SECONDS")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Command::new("daemon")
+                .about("Sync every upload entry with a schedule on its own interval."),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show the last sync of every upload entry per cloud.")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the status as JSON."),
+                ),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Inspect and maintain the sync cache.")
+                .subcommand_required(true)
+                .subcommand(Command::new("list").about("List every entry in the cache."))
+                .subcommand(
+                    Command::new("prune")
+                        .about("Remove cache entries that are no longer in upload.toml."),
+                )
+                .subcommand(
+                    Command::new("forget")
+                        .about("Remove one entry so the next sync uploads it again.")
+                        .arg(
+                            Arg::new("entry")
+                                .help("Upload entry name or path.")
+                                .value_name("ENTRY")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("reset")
+                        .about("Empty the cache so the next sync uploads everything again."),
+                )
+                .subcommand(
+                    Command::new("export").about("Print the cache.").arg(
+                        Arg::new("json")
+                            .long("json")
+                            .action(ArgAction::SetTrue)
+                            .help("Print as JSON, the only export format."),
+                    ),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Merge entries exported with `cache export --json`.")
+                        .arg(
+                            Arg::new("file")
+                                .help("Exported JSON file, - reads stdin.")
+                                .value_name("FILE")
+                                .value_parser(value_parser!(PathBuf))
+                                .value_hint(ValueHint::FilePath)
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("install-service")
+                .about("Write systemd user units that run cl_sync unattended.")
+                .arg(
+                    Arg::new("timer")
+                        .long("timer")
+                        .help("Run --sync on a systemd calendar spec (default DAILY).")
+                        .value_name("ON_CALENDAR")
+                        .conflicts_with("watch"),
+                )
+                .arg(
+                    Arg::new("watch")
+                        .long("watch")
+                        .action(ArgAction::SetTrue)
+                        .help("Run watch mode as a long running service."),
+                ),
+        )
+        .subcommand(
+            Command::new("uninstall-service").about("Remove the systemd user units of cl_sync."),
+        )
+        .subcommand(
+            Command::new("quota").about("Show the space used and left on every cloud provider."),
+        )
+        .subcommand(
+            Command::new("bwlimit")
+                .about("Show or change the upload limit of a running sync, watch or daemon.")
+                .arg(
+                    Arg::new("rate")
+                        .help("New limit, e.g. 512k, off or \"08:00,512k 23:00,off\".")
+                        .value_name("RATE"),
+                ),
+        )
 }
 pub fn print_completions<G: Generator>(gen: G, cmd: &mut clap::Command) {
     generate(gen, cmd, cmd.get_name().to_string(), &mut io::stdout());
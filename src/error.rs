@@ -1,14 +1,384 @@
 use std::path::PathBuf;
 use thiserror::Error;
 
+// Process exit codes, one per error category.
+// 2 is left to clap for invalid arguments.
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_CONFIG: i32 = 3;
+pub const EXIT_CACHE: i32 = 4;
+pub const EXIT_DAEMON: i32 = 5;
+pub const EXIT_RC: i32 = 6;
+pub const EXIT_JOB: i32 = 7;
+pub const EXIT_MOUNT: i32 = 8;
+pub const EXIT_FILESYSTEM: i32 = 9;
+// some uploads failed while others went through
+pub const EXIT_PARTIAL: i32 = 10;
+pub const EXIT_TIMEOUT: i32 = 11;
+pub const EXIT_QUOTA: i32 = 12;
+
+#[derive(Debug, Error)]
+pub enum ClSyncError {
+    #[error(transparent)]
+    Config(#[from] TomlError),
+
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+
+    #[error(transparent)]
+    Daemon(#[from] DaemonError),
+
+    #[error(transparent)]
+    Rc(#[from] RcError),
+
+    #[error("rclone job {job_id} failed: {error}")]
+    Job { job_id: u64, error: RcloneError },
+
+    #[error("Failed to mount {remote} at {mount_point}: {reason}")]
+    Mount {
+        remote: String,
+        mount_point: String,
+        reason: String,
+    },
+
+    #[error("Failed to access {}", path.display())]
+    Filesystem {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    // One entry failed on some of its clouds, each with its own error
+    #[error("Failed to sync {path} to {}", format_failures(failures))]
+    Sync {
+        path: String,
+        failures: Vec<(String, ClSyncError)>,
+    },
+
+    #[error("{failed} of {attempted} uploads failed")]
+    PartialFailure { failed: usize, attempted: usize },
+
+    #[error("rclone job {job_id} timed out and was stopped")]
+    JobTimeout { job_id: u64 },
+
+    #[error("The run timed out before this entry was synced")]
+    RunTimeout,
+
+    #[error("{cloud} has {free} bytes left, uploading needs up to {needed}")]
+    InsufficientSpace {
+        cloud: String,
+        needed: u64,
+        free: u64,
+    },
+}
+
+impl ClSyncError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClSyncError::Config(_) => EXIT_CONFIG,
+            ClSyncError::Cache(_) => EXIT_CACHE,
+            ClSyncError::Daemon(_) => EXIT_DAEMON,
+            ClSyncError::Rc(_) => EXIT_RC,
+            ClSyncError::Job { .. } => EXIT_JOB,
+            ClSyncError::Mount { .. } => EXIT_MOUNT,
+            ClSyncError::Filesystem { .. } => EXIT_FILESYSTEM,
+            ClSyncError::Sync { failures, .. } => failures
+                .first()
+                .map_or(EXIT_JOB, |(_, error)| error.exit_code()),
+            ClSyncError::PartialFailure { .. } => EXIT_PARTIAL,
+            ClSyncError::JobTimeout { .. } | ClSyncError::RunTimeout => EXIT_TIMEOUT,
+            ClSyncError::InsufficientSpace { .. } => EXIT_QUOTA,
+        }
+    }
+
+    // A job or the whole run took longer than it may
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            ClSyncError::JobTimeout { .. } | ClSyncError::RunTimeout
+        )
+    }
+
+    // Worth submitting the job again: the daemon didn't answer
+    // or rclone failed with an error that isn't permanent
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClSyncError::Rc(RcError::Request { .. }) => true,
+            ClSyncError::Rc(RcError::Status { error, .. }) => !error.is_permanent(),
+            ClSyncError::Job { error, .. } => !error.is_permanent(),
+            _ => false,
+        }
+    }
+}
+
+fn format_failures(failures: &[(String, ClSyncError)]) -> String {
+    failures
+        .iter()
+        .map(|(cloud, error)| format!("{} ({})", cloud, error))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Exit code for an error returned from main, taken from the first
+// typed error in its chain so added context doesn't change it
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<ClSyncError>() {
+            return error.exit_code();
+        }
+        if cause.is::<TomlError>() {
+            return EXIT_CONFIG;
+        }
+        if cause.is::<CacheError>() {
+            return EXIT_CACHE;
+        }
+        if cause.is::<DaemonError>() {
+            return EXIT_DAEMON;
+        }
+        if cause.is::<RcError>() {
+            return EXIT_RC;
+        }
+        if cause.is::<RcloneError>() {
+            return EXIT_JOB;
+        }
+    }
+    EXIT_FAILURE
+}
+
 #[derive(Debug, Error)]
 pub enum TomlError {
-    #[error("Failed to parse TOML file: {0}")]
-    ParseError(#[from] toml::de::Error),
+    #[error("Failed to parse {}: {error}", path.display())]
+    ParseError {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
 
-    #[error("Failed to read TOML file: {0}")]
-    FileReadError(#[from] std::io::Error),
+    #[error("Failed to read TOML file {}", path.display())]
+    FileReadError {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
 
     #[error("TOML file not found: {0}")]
     FileNotFound(PathBuf),
+
+    #[error("The '{0}' section is missing or empty in the upload.toml file")]
+    MissingSection(&'static str),
+
+    #[error("Cloud {0} is not listed in [cloud_providers]")]
+    UnknownCloud(String),
+
+    #[error("Invalid {setting} in [{section}]: {reason}")]
+    InvalidSetting {
+        section: String,
+        setting: &'static str,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("Cache file is corrupt")]
+    Corrupt(#[source] bincode::Error),
+
+    #[error("Cache schema version {found} is newer than the supported version {supported}")]
+    NewerVersion { found: u32, supported: u32 },
+
+    #[error("Failed to encode cache")]
+    Encode(#[source] bincode::Error),
+
+    #[error("Can not write cache to {path}")]
+    Write {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    #[error("rclone is not installed or not in PATH")]
+    NotInstalled,
+
+    #[error("Failed to start the rclone daemon")]
+    Start(#[source] std::io::Error),
+
+    #[error("The rclone daemon did not answer on port {port} within {secs}s")]
+    NotReachable { port: u16, secs: u64 },
+
+    #[error("No cl_sync run is active, nothing answers on port {port}")]
+    NotRunning { port: u16 },
+}
+
+// Talking to the rclone daemon over HTTP
+#[derive(Debug, Error)]
+pub enum RcError {
+    #[error("Failed to reach the rclone daemon for {command}")]
+    Request {
+        command: String,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    #[error("rclone {command} failed with status {status}: {error}")]
+    Status {
+        command: String,
+        status: u16,
+        error: RcloneError,
+    },
+
+    #[error("rclone {command} response has no {field}")]
+    MissingField {
+        command: String,
+        field: &'static str,
+    },
+
+    #[error("Unexpected response from rclone {command}")]
+    Decode {
+        command: String,
+        #[source]
+        source: reqwest::Error,
+    },
+}
+
+// The `error` field of an rclone response or job, sorted by
+// what went wrong so callers can tell an expired login from a full remote
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum RcloneError {
+    #[error("authentication failed: {0}")]
+    Auth(String),
+
+    #[error("quota exceeded: {0}")]
+    Quota(String),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("network error: {0}")]
+    Network(String),
+
+    #[error("{0}")]
+    Other(String),
+}
+
+impl RcloneError {
+    // Errors that come back on every retry until someone fixes them
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            RcloneError::Auth(_) | RcloneError::Quota(_) | RcloneError::NotFound(_)
+        )
+    }
+
+    // The HTTP status of a failed call decides when it names the cause,
+    // otherwise the message does
+    pub fn from_status(status: u16, message: &str) -> Self {
+        match status {
+            401 => RcloneError::Auth(message.to_string()),
+            507 => RcloneError::Quota(message.to_string()),
+            _ => Self::from_message(message),
+        }
+    }
+
+    pub fn from_message(message: &str) -> Self {
+        let lower = message.to_lowercase();
+        let contains_any = |patterns: &[&str]| patterns.iter().any(|p| lower.contains(p));
+        let message = message.to_string();
+
+        if contains_any(&[
+            "invalid_grant",
+            "unauthorized",
+            "unauthenticated",
+            "token expired",
+            "couldn't fetch token",
+            "authentication",
+        ]) {
+            RcloneError::Auth(message)
+        } else if contains_any(&[
+            "quota",
+            "insufficient storage",
+            "insufficient_space",
+            "no space left",
+        ]) {
+            RcloneError::Quota(message)
+        } else if contains_any(&["not found", "didn't find section", "no such file"]) {
+            RcloneError::NotFound(message)
+        } else if contains_any(&[
+            "connection refused",
+            "connection reset",
+            "no such host",
+            "timeout",
+            "timed out",
+            "network is unreachable",
+        ]) {
+            RcloneError::Network(message)
+        } else {
+            RcloneError::Other(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_test {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_classify_rclone_error() {
+        assert!(matches!(
+            RcloneError::from_message("couldn't fetch token: invalid_grant"),
+            RcloneError::Auth(_)
+        ));
+        assert!(matches!(
+            RcloneError::from_message("googleapi: Error 403: storageQuotaExceeded"),
+            RcloneError::Quota(_)
+        ));
+        assert!(matches!(
+            RcloneError::from_message("directory not found"),
+            RcloneError::NotFound(_)
+        ));
+        assert!(matches!(
+            RcloneError::from_message("dial tcp: lookup x: no such host"),
+            RcloneError::Network(_)
+        ));
+        assert_eq!(
+            RcloneError::from_message("something else"),
+            RcloneError::Other("something else".to_string())
+        );
+        // numbers in a message are no status
+        assert_eq!(
+            RcloneError::from_message("copied 401 files, 507 left"),
+            RcloneError::Other("copied 401 files, 507 left".to_string())
+        );
+        assert_eq!(
+            RcloneError::from_status(401, "bad credentials"),
+            RcloneError::Auth("bad credentials".to_string())
+        );
+        assert_eq!(
+            RcloneError::from_status(507, "upload failed"),
+            RcloneError::Quota("upload failed".to_string())
+        );
+    }
+
+    #[test]
+    fn test_exit_code_survives_context() {
+        let error: anyhow::Result<()> = Err(DaemonError::NotInstalled.into());
+        assert_eq!(
+            exit_code(&error.context("starting").unwrap_err()),
+            EXIT_DAEMON
+        );
+
+        let sync = ClSyncError::Sync {
+            path: "/home/user/vault".to_string(),
+            failures: vec![(
+                "dge".to_string(),
+                ClSyncError::Job {
+                    job_id: 1,
+                    error: RcloneError::from_message("quota exceeded"),
+                },
+            )],
+        };
+        assert_eq!(sync.exit_code(), EXIT_JOB);
+        assert_eq!(exit_code(&anyhow::anyhow!("plain")), EXIT_FAILURE);
+    }
 }
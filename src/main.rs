@@ -2,6 +2,8 @@ pub mod cl_sync;
 pub mod cli;
 pub mod error;
 pub mod operations;
+#[cfg(test)]
+mod test_fixtures;
 
 use anyhow::Result;
 use clap_complete::Shell;
@@ -12,7 +14,14 @@ use tracing_subscriber::FmtSubscriber;
 use crate::operations::toml;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(error::exit_code(&e));
+    }
+}
+
+async fn run() -> Result<()> {
     let matches = cli::build_cli().get_matches();
 
     let log_level = if matches.get_flag("debug") {
@@ -30,18 +39,95 @@ async fn main() -> Result<()> {
         debug!("Running in non-interactive mode.");
     }
 
+    // Everything that starts rclone or writes the cache runs one at a time
+    let needs_lock = matches.get_flag("synchronise")
+        || matches.contains_id("upload")
+        || matches!(matches.subcommand_name(), Some("watch" | "daemon"))
+        || matches!(
+            matches
+                .subcommand_matches("cache")
+                .and_then(|cache| cache.subcommand_name()),
+            Some("prune" | "forget" | "reset" | "import")
+        );
+    let _lock = if needs_lock {
+        Some(cl_sync::lock::InstanceLock::acquire(matches.get_flag("wait")).await?)
+    } else {
+        None
+    };
+
     if matches.get_flag("synchronise") {
-        let parsed_toml = toml::TomlParser::new().await?;
-        cl_sync::begin_sync(&parsed_toml).await?;
+        let parsed_toml = parse_toml(&matches).await?;
+        cl_sync::begin_sync(&parsed_toml, matches.get_flag("fail_fast")).await?;
     }
 
     if let Some(path) = matches.get_one::<PathBuf>("upload") {
-        let parsed_toml = toml::TomlParser::new().await?;
+        let parsed_toml = parse_toml(&matches).await?;
         cl_sync::begin_upload(&parsed_toml, path.to_path_buf(), nointer).await?;
     }
 
+    if let Some(("watch", sub_matches)) = matches.subcommand() {
+        let parsed_toml = parse_toml(&matches).await?;
+        let quiet_period = sub_matches.get_one::<u64>("quiet_period").copied();
+        cl_sync::watch::begin_watch(&parsed_toml, quiet_period).await?;
+    }
+
+    if let Some(("daemon", _)) = matches.subcommand() {
+        let parsed_toml = parse_toml(&matches).await?;
+        cl_sync::daemon::begin_daemon(&parsed_toml).await?;
+    }
+
+    if let Some(("status", sub_matches)) = matches.subcommand() {
+        let parsed_toml = toml::TomlParser::new().await?;
+        cl_sync::status::begin_status(&parsed_toml, sub_matches.get_flag("json")).await?;
+    }
+
+    if let Some(("cache", sub_matches)) = matches.subcommand() {
+        let parsed_toml = toml::TomlParser::new().await?;
+        match sub_matches.subcommand() {
+            Some(("list", _)) => cl_sync::cache_commands::list(&parsed_toml).await?,
+            Some(("prune", _)) => cl_sync::cache_commands::prune(&parsed_toml).await?,
+            Some(("forget", forget)) => {
+                let entry = forget.get_one::<String>("entry").unwrap();
+                cl_sync::cache_commands::forget(&parsed_toml, entry).await?
+            }
+            Some(("reset", _)) => cl_sync::cache_commands::reset(&parsed_toml).await?,
+            Some(("export", _)) => cl_sync::cache_commands::export_json(&parsed_toml).await?,
+            Some(("import", import)) => {
+                let file = import.get_one::<PathBuf>("file").unwrap();
+                cl_sync::cache_commands::import_json(&parsed_toml, file).await?
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(("quota", _)) = matches.subcommand() {
+        let parsed_toml = parse_toml(&matches).await?;
+        cl_sync::quota::begin_quota(&parsed_toml, matches.get_flag("wait")).await?;
+    }
+
+    if let Some(("bwlimit", sub_matches)) = matches.subcommand() {
+        let rate = sub_matches.get_one::<String>("rate");
+        cl_sync::bwlimit::change_bwlimit(rate.map(String::as_str)).await?;
+    }
+
+    if let Some(("install-service", sub_matches)) = matches.subcommand() {
+        let mode = if sub_matches.get_flag("watch") {
+            cl_sync::service::ServiceMode::Watch
+        } else {
+            let on_calendar = sub_matches
+                .get_one::<String>("timer")
+                .map_or("DAILY", String::as_str);
+            cl_sync::service::ServiceMode::Timer(on_calendar.to_string())
+        };
+        cl_sync::service::install_service(mode).await?;
+    }
+
+    if let Some(("uninstall-service", _)) = matches.subcommand() {
+        cl_sync::service::uninstall_service().await?;
+    }
+
     if matches.get_flag("check") {
-        let _ = cl_sync::check_last_update();
+        cl_sync::check_last_update().await;
     }
 
     if let Some(generator) = matches.get_one::<Shell>("generator") {
@@ -51,3 +137,12 @@ async fn main() -> Result<()> {
     }
     Ok(())
 }
+
+// upload.toml with the overrides given on the command line
+async fn parse_toml(matches: &clap::ArgMatches) -> Result<toml::TomlParser> {
+    let mut parsed_toml = toml::TomlParser::new().await?;
+    if let Some(rate) = matches.get_one::<String>("bwlimit") {
+        parsed_toml.override_bwlimit(rate);
+    }
+    Ok(parsed_toml)
+}
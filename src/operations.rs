@@ -1,4 +1,6 @@
 pub mod cl_sync_cache;
+pub mod job_tracker;
+pub mod rc;
 pub mod rclone;
 pub mod sys_ops;
 pub mod toml;
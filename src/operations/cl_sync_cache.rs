@@ -1,58 +1,218 @@
+use crate::error::CacheError;
 use crate::operations::sys_ops;
-use crate::operations::toml::{TomlParser, TomlSection, TomlToParse};
+use crate::operations::toml::{TomlParser, TomlSection, TomlToParse, TomlUpload};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use chrono::{DateTime, Local};
 use hashbrown::HashMap;
 use home::home_dir;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+// Every cache file starts with the magic bytes and the schema version
+// followed by the bincode encoded CacheFile.
+// Files without the header are the original headerless layout (version 1),
+// a map of ToUploadV1 keyed by file_or_dir_path.
+const CACHE_MAGIC: &[u8; 8] = b"CLSYNCCH";
+pub const CACHE_VERSION: u32 = 2;
+const CACHE_HEADER_LEN: usize = CACHE_MAGIC.len() + 4;
 
 // This is the bincode file that gets loaded in to memory
 pub struct ClCache {
     pub data: Arc<Mutex<HashMap<String, ToUpload>>>,
+    pub schedule: Arc<Mutex<HashMap<String, ScheduleRun>>>,
     pub cache_storage_path: String,
 }
 
 // This is each individual representation of files
-// that need to be checked and uploaded
-// file_path: where the file or dir lives
+// that need to be checked and uploaded, keyed by its [upload.<id>] name
+// file_path: canonical path of where the file or dir lives
+// last_saved: last time any of its clouds was synced
+// clouds: sync state per cloud name
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ToUpload {
     pub file_path: String,
     pub last_saved: DateTime<Local>,
+    pub clouds: HashMap<String, CloudSyncState>,
+}
+
+// Sync state of one entry on one cloud
+// manifest_hash: local state the last successful sync uploaded
+// archive_hash: sha256 of the archive it uploaded, for entries with an archive
+// destination: where it uploaded to, e.g. "dge:desk/Vault"
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CloudSyncState {
+    pub last_synced: Option<DateTime<Local>>,
+    pub manifest_hash: Option<String>,
+    pub last_attempt: Option<DateTime<Local>>,
+    pub last_error: Option<String>,
+    pub archive_hash: Option<String>,
+    pub destination: Option<String>,
+}
+
+// ToUpload of the headerless cache, one timestamp for all clouds
+#[derive(Debug, Serialize, Deserialize)]
+struct ToUploadV1 {
+    file_path: String,
+    last_saved: DateTime<Local>,
+}
+
+impl From<ToUploadV1> for ToUpload {
+    fn from(old: ToUploadV1) -> Self {
+        Self {
+            file_path: old.file_path,
+            last_saved: old.last_saved,
+            clouds: HashMap::new(),
+        }
+    }
+}
+
+// Last and next run of an entry with a schedule, used by `cl_sync daemon`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ScheduleRun {
+    pub last_run: Option<DateTime<Local>>,
+    pub next_run: Option<DateTime<Local>>,
+}
+
+// What gets written after the header of the cache file
+// keyed_by_path: loaded from the headerless cache that keyed records by path,
+// rekey_by_entry has to run before the records are used
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct CacheFile {
+    pub data: HashMap<String, ToUpload>,
+    pub schedule: HashMap<String, ScheduleRun>,
+    #[serde(skip)]
+    pub keyed_by_path: bool,
+}
+
+impl CacheFile {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut encoded = Vec::with_capacity(CACHE_HEADER_LEN);
+        encoded.extend_from_slice(CACHE_MAGIC);
+        encoded.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+        encoded.extend(bincode::serialize(self).map_err(CacheError::Encode)?);
+        Ok(encoded)
+    }
+
+    // Decode any known version of the cache file, migrating older layouts
+    pub fn decode(buffer: &[u8]) -> Result<Self> {
+        if buffer.len() < CACHE_HEADER_LEN || &buffer[..CACHE_MAGIC.len()] != CACHE_MAGIC {
+            return Self::migrate_v1(buffer);
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&buffer[CACHE_MAGIC.len()..CACHE_HEADER_LEN]);
+        let payload = &buffer[CACHE_HEADER_LEN..];
+
+        match u32::from_le_bytes(version) {
+            CACHE_VERSION => Ok(bincode::deserialize(payload).map_err(CacheError::Corrupt)?),
+            version => Err(CacheError::NewerVersion {
+                found: version,
+                supported: CACHE_VERSION,
+            }
+            .into()),
+        }
+    }
+
+    // Version 1 was a headerless bincode map of path -> ToUploadV1,
+    // its clouds start without state
+    fn migrate_v1(buffer: &[u8]) -> Result<Self> {
+        let data: HashMap<String, ToUploadV1> =
+            bincode::deserialize(buffer).map_err(CacheError::Corrupt)?;
+        debug!("Migrating cache from version 1");
+        Ok(Self {
+            data: data
+                .into_iter()
+                .map(|(key, file)| (key, file.into()))
+                .collect(),
+            schedule: HashMap::new(),
+            keyed_by_path: true,
+        })
+    }
+
+    // Move records keyed by path to the upload entry with that path.
+    // Paths match with or without a trailing slash and through symlinks,
+    // records of paths no entry uses keep their key until pruned.
+    pub fn rekey_by_entry(&mut self, upload_list: &HashMap<String, TomlUpload>) {
+        for (entry, to_up) in upload_list {
+            let path_key = self
+                .data
+                .keys()
+                .chain(self.schedule.keys())
+                .find(|key| same_path(key, &to_up.file_or_dir_path))
+                .cloned();
+            let Some(path_key) = path_key else {
+                continue;
+            };
+            debug!("Moving cache record {} to [upload.{}]", path_key, entry);
+
+            if let Some(mut file) = self.data.remove(&path_key) {
+                file.file_path = std::fs::canonicalize(&to_up.file_or_dir_path)
+                    .map(|path| path.to_string_lossy().to_string())
+                    .unwrap_or_else(|_| to_up.file_or_dir_path.to_string());
+                self.data.insert(entry.to_string(), file);
+            }
+            if let Some(run) = self.schedule.remove(&path_key) {
+                self.schedule.insert(entry.to_string(), run);
+            }
+        }
+        self.keyed_by_path = false;
+    }
+}
+
+fn same_path(a: &str, b: &str) -> bool {
+    if Path::new(a).components().eq(Path::new(b).components()) {
+        return true;
+    }
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
 }
 
 impl ClCache {
     pub async fn new(parsed_toml: &TomlParser) -> Result<Self> {
         let _ = sys_ops::config_dir_exists().await;
 
-        let data = Self::load_from_file(&mut parsed_toml.clone())
-            .await
-            .unwrap_or_default();
+        let mut parsed_toml = parsed_toml.clone();
         // Get the CacheDir section and extract the path
-        let cache_storage_path = match parsed_toml
+        let mut cache_storage_path = match parsed_toml
             .get_section_from_toml(TomlSection::CacheDir)
             .await
         {
             Ok(TomlToParse::CacheDir(dir)) => dir,
-
-            _ => panic!("Unexpected section type for CacheDir"),
+            // without a [cache_dir] create_cache_file falls back to ~/.config/cl_sync
+            _ => String::new(),
         };
+        if !Self::file_exists(&cache_storage_path).await {
+            Self::create_cache_file(&mut cache_storage_path, &mut parsed_toml).await?;
+        }
+
+        let mut cache_file = Self::load_from_file(&cache_storage_path).await;
+        if cache_file.keyed_by_path {
+            let upload_list = match parsed_toml.get_section_from_toml(TomlSection::Upload).await {
+                Ok(TomlToParse::Upload(dir)) => dir,
+                _ => HashMap::new(),
+            };
+            cache_file.rekey_by_entry(&upload_list);
+            Self::write_atomic(&cache_storage_path, &cache_file.encode()?).await?;
+        }
+
         Ok(ClCache {
-            data: Arc::new(Mutex::new(HashMap::from(data))),
+            data: Arc::new(Mutex::new(cache_file.data)),
+            schedule: Arc::new(Mutex::new(cache_file.schedule)),
             cache_storage_path,
         })
     }
 
-    pub async fn insert(&self, upload: ToUpload) {
+    pub async fn insert(&self, key: &str, upload: ToUpload) {
         let mut data = self.data.lock().await; // Lock the HashMap
-        data.insert(upload.file_path.to_string(), upload); // Perform the insertion
+        data.insert(key.to_string(), upload); // Perform the insertion
     }
 
     pub async fn remove(&self, key: &str) -> Option<ToUpload> {
@@ -65,28 +225,72 @@ impl ClCache {
         data.get(key).cloned() // Return a cloned value to avoid borrowing issues
     }
 
-    async fn load_from_file(parsed_toml: &mut TomlParser) -> Result<HashMap<String, ToUpload>> {
-        let mut cache_path = match parsed_toml
-            .get_section_from_toml(TomlSection::CacheDir)
-            .await
-        {
-            Ok(TomlToParse::CacheDir(dir)) => dir,
-            Ok(_) => {
-                return Err(anyhow!("Unexpected section returned for 'CacheDir'"));
-                // Early return to handle unexpected cases
-            }
-            Err(err) => {
-                return Err(err); // Early return to propagate the error
+    pub async fn keys(&self) -> Vec<String> {
+        let data = self.data.lock().await;
+        let mut keys: Vec<String> = data.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    // Drop every entry so the next sync uploads everything again
+    pub async fn clear(&self) {
+        self.data.lock().await.clear();
+        self.schedule.lock().await.clear();
+    }
+
+    // Copy of everything in the cache, as it would be written to disk
+    pub async fn to_cache_file(&self) -> CacheFile {
+        CacheFile {
+            data: self.data.lock().await.clone(),
+            schedule: self.schedule.lock().await.clone(),
+            keyed_by_path: false,
+        }
+    }
+
+    // Add every record of cache_file, replacing records with the same key
+    pub async fn merge(&self, cache_file: CacheFile) {
+        self.data.lock().await.extend(cache_file.data);
+        self.schedule.lock().await.extend(cache_file.schedule);
+    }
+
+    pub async fn remove_schedule(&self, key: &str) -> Option<ScheduleRun> {
+        let mut schedule = self.schedule.lock().await;
+        schedule.remove(key)
+    }
+
+    pub async fn get_schedule(&self, key: &str) -> Option<ScheduleRun> {
+        let schedule = self.schedule.lock().await;
+        schedule.get(key).cloned()
+    }
+
+    pub async fn insert_schedule(&self, key: &str, run: ScheduleRun) {
+        let mut schedule = self.schedule.lock().await;
+        schedule.insert(key.to_string(), run);
+    }
+
+    // A missing file is an empty cache. A file that can't be decoded
+    // is moved aside to cache.bin.corrupt and the cache is rebuilt from scratch.
+    async fn load_from_file(cache_path: &str) -> CacheFile {
+        let buffer = match fs::read(cache_path).await {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                debug!("Cache file {} not read: {}", cache_path, e);
+                return CacheFile::default();
             }
         };
-        if !Self::file_exists(&cache_path).await {
-            let _ = Self::create_cache_file(&mut cache_path, parsed_toml).await;
+
+        match CacheFile::decode(&buffer) {
+            Ok(cache_file) => cache_file,
+            Err(e) => {
+                let corrupt_path = format!("{}.corrupt", cache_path);
+                warn!(
+                    "Cache file {} is unreadable ({:#}), moving it to {} and rebuilding the cache",
+                    cache_path, e, corrupt_path
+                );
+                let _ = fs::rename(cache_path, &corrupt_path).await;
+                CacheFile::default()
+            }
         }
-        let mut file = File::open(cache_path).await?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).await?;
-        let data: HashMap<String, ToUpload> = bincode::deserialize(&buffer).unwrap();
-        Ok(data)
     }
 
     async fn file_exists(path: &str) -> bool {
@@ -100,7 +304,11 @@ impl ClCache {
         cache_storage_path: &mut String,
         parsed_toml: &mut TomlParser,
     ) -> Result<()> {
-        if !Self::directory_exists(&cache_storage_path).await {
+        let cache_dir = Path::new(cache_storage_path.as_str())
+            .parent()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !Self::directory_exists(&cache_dir).await {
             println!("directory_exists no");
             let home_path = home_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
             let config_path = home_path.join(".config/cl_sync/cache.bin");
@@ -108,12 +316,7 @@ impl ClCache {
             parsed_toml.update_cache_dir().await?;
             *cache_storage_path = config_path.to_string_lossy().to_string();
         }
-        let encoded: Vec<u8> = bincode::serialize("").unwrap();
-        let mut file = File::create(cache_storage_path)
-            .await
-            .expect("Can not write cache to path");
-        let _ = file.write_all(&encoded).await;
-        Ok(())
+        Self::write_atomic(cache_storage_path, &CacheFile::default().encode()?).await
     }
 
     async fn directory_exists(path: &str) -> bool {
@@ -124,17 +327,35 @@ impl ClCache {
     }
 
     pub async fn save_to_file(&self) -> Result<()> {
-        // Lock the Mutex to access the data
-        let data = self.data.lock().await;
-        let encoded: Vec<u8> = bincode::serialize(&*data).unwrap();
-        let mut file = File::create(&self.cache_storage_path)
-            .await
-            .expect("Can not write cache to path");
-        let _ = file
-            .write_all(&encoded)
-            .await
-            .expect("Can not write cache to path");
+        let cache_file = self.to_cache_file().await;
+        Self::write_atomic(&self.cache_storage_path, &cache_file.encode()?).await
+    }
 
+    // Write to a temp file next to the cache, fsync and rename over the old file
+    // so a crash leaves either the old or the new cache, never half of one
+    async fn write_atomic(cache_path: &str, encoded: &[u8]) -> Result<()> {
+        let tmp_path = format!("{}.tmp", cache_path);
+        let write_error = |source| CacheError::Write {
+            path: tmp_path.to_string(),
+            source,
+        };
+        let mut file = File::create(&tmp_path).await.map_err(write_error)?;
+        file.write_all(encoded).await.map_err(write_error)?;
+        file.sync_all().await.map_err(write_error)?;
+        drop(file);
+
+        fs::rename(&tmp_path, cache_path)
+            .await
+            .map_err(|source| CacheError::Write {
+                path: cache_path.to_string(),
+                source,
+            })?;
+        // fsync the dir too so the rename itself survives a crash
+        if let Some(dir) = Path::new(cache_path).parent() {
+            if let Ok(dir) = File::open(dir).await {
+                let _ = dir.sync_all().await;
+            }
+        }
         Ok(())
     }
 }
@@ -147,6 +368,126 @@ mod cache_test {
     async fn test_get_home() {
         let _ = sys_ops::config_dir_exists().await;
     }
+
+    fn temp_cache_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("cl_sync_{}_{}.bin", name, std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn to_upload(path: &str) -> ToUpload {
+        ToUpload {
+            file_path: path.to_string(),
+            last_saved: Local::now(),
+            clouds: HashMap::new(),
+        }
+    }
+
+    fn to_upload_v1(path: &str) -> ToUploadV1 {
+        ToUploadV1 {
+            file_path: path.to_string(),
+            last_saved: Local::now(),
+        }
+    }
+
+    #[test]
+    fn test_migrate_headerless_cache() -> Result<()> {
+        let mut data = HashMap::new();
+        data.insert(
+            "/home/user/vault".to_string(),
+            to_upload_v1("/home/user/vault"),
+        );
+        let legacy = bincode::serialize(&data)?;
+
+        let cache_file = CacheFile::decode(&legacy)?;
+        assert!(cache_file.data["/home/user/vault"].clouds.is_empty());
+        assert!(cache_file.keyed_by_path);
+
+        // the old create_cache_file wrote an encoded empty string
+        let legacy_empty = bincode::serialize("")?;
+        assert!(CacheFile::decode(&legacy_empty)?.data.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_by_entry() {
+        let mut cache_file = CacheFile::default();
+        cache_file.data.insert(
+            "/home/user/vault/".to_string(),
+            to_upload("/home/user/vault/"),
+        );
+        cache_file
+            .data
+            .insert("/home/user/old".to_string(), to_upload("/home/user/old"));
+        cache_file.schedule.insert(
+            "/home/user/vault/".to_string(),
+            ScheduleRun {
+                last_run: Some(Local::now()),
+                next_run: Some(Local::now()),
+            },
+        );
+
+        let mut upload_list = HashMap::new();
+        upload_list.insert(
+            "vault".to_string(),
+            crate::test_fixtures::entry("/home/user/vault", &["dge"]),
+        );
+        cache_file.rekey_by_entry(&upload_list);
+
+        assert_eq!(cache_file.data["vault"].file_path, "/home/user/vault");
+        assert!(cache_file.schedule.contains_key("vault"));
+        // records no entry points at are left for `cache prune`
+        assert!(cache_file.data.contains_key("/home/user/old"));
+        assert!(!cache_file.keyed_by_path);
+    }
+
+    #[test]
+    fn test_reject_corrupt_and_newer_cache() -> Result<()> {
+        let mut encoded = CacheFile::default().encode()?;
+        encoded.truncate(CACHE_HEADER_LEN + 3);
+        assert!(CacheFile::decode(&encoded).is_err());
+
+        let mut newer = CacheFile::default().encode()?;
+        newer[CACHE_MAGIC.len()..CACHE_HEADER_LEN]
+            .copy_from_slice(&(CACHE_VERSION + 1).to_le_bytes());
+        assert!(CacheFile::decode(&newer).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_and_reload_cache() -> Result<()> {
+        let cache_path = temp_cache_path("reload");
+        let cache = ClCache {
+            data: Arc::new(Mutex::new(HashMap::new())),
+            schedule: Arc::new(Mutex::new(HashMap::new())),
+            cache_storage_path: cache_path.clone(),
+        };
+        cache.insert("vault", to_upload("/home/user/vault")).await;
+        cache.save_to_file().await?;
+        assert!(!Path::new(&format!("{}.tmp", cache_path)).exists());
+
+        let cache_file = ClCache::load_from_file(&cache_path).await;
+        assert!(cache_file.data.contains_key("vault"));
+        assert!(!cache_file.keyed_by_path);
+
+        fs::remove_file(&cache_path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_cache_is_rebuilt() -> Result<()> {
+        let cache_path = temp_cache_path("corrupt");
+        fs::write(&cache_path, b"CLSYNCCH\x02\x00\x00\x00garbage").await?;
+
+        let cache_file = ClCache::load_from_file(&cache_path).await;
+        assert!(cache_file.data.is_empty());
+        let corrupt_path = format!("{}.corrupt", cache_path);
+        assert!(Path::new(&corrupt_path).exists());
+
+        fs::remove_file(&corrupt_path).await?;
+        Ok(())
+    }
 }
 
 #[tokio::test]
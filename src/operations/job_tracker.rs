@@ -0,0 +1,259 @@
+use futures::stream::{self, Stream};
+use hashbrown::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot};
+use tracing::debug;
+
+use crate::error::{RcError, RcloneError};
+use crate::operations::rc::RcClient;
+use crate::operations::rclone::{self, JobStatus};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Status checks in a row that may fail before a job is given up on
+const MAX_STATUS_ERRORS: u32 = 10;
+// Completions a slow subscriber may fall behind before it misses some
+const EVENT_CAPACITY: usize = 256;
+
+// A job that finished, sent to every subscriber of the tracker
+#[derive(Debug, Clone)]
+pub struct JobEvent {
+    pub job_id: u64,
+    pub status: JobStatus,
+}
+
+#[derive(Default)]
+struct TrackedJob {
+    waiters: Vec<oneshot::Sender<JobStatus>>,
+    status_errors: u32,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    jobs: HashMap<u64, TrackedJob>,
+    polling: bool,
+}
+
+// Waits for rclone jobs to finish. A single background task polls every
+// outstanding job at once, asking job/list which jobs still run and
+// job/status only for the ones that are done. It stops when nothing is
+// left to wait for and starts again with the next job.
+// Cloning is cheap, clones share the jobs and the subscribers.
+#[derive(Clone)]
+pub struct JobTracker {
+    state: Arc<Mutex<TrackerState>>,
+    events: broadcast::Sender<JobEvent>,
+    client: RcClient,
+}
+
+impl Default for JobTracker {
+    fn default() -> Self {
+        Self::new(RcClient::default())
+    }
+}
+
+impl JobTracker {
+    // Tracks the jobs of the daemon client talks to
+    pub fn new(client: RcClient) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            state: Arc::new(Mutex::new(TrackerState::default())),
+            events,
+            client,
+        }
+    }
+
+    // Final status of the job
+    pub async fn wait(&self, job_id: u64) -> JobStatus {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.jobs.entry(job_id).or_default().waiters.push(tx);
+            if !state.polling {
+                state.polling = true;
+                tokio::spawn(self.clone().poll_loop());
+            }
+        }
+        rx.await
+            .unwrap_or_else(|_| lost_job("the job tracker stopped"))
+    }
+
+    // Final status of every job
+    pub async fn wait_all(&self, job_ids: &[u64]) -> HashMap<u64, JobStatus> {
+        let statuses =
+            futures::future::join_all(job_ids.iter().map(|job_id| self.wait(*job_id))).await;
+        job_ids.iter().copied().zip(statuses).collect()
+    }
+
+    // Every job that finishes from now on
+    pub fn completions(&self) -> impl Stream<Item = JobEvent> {
+        stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("Missed {} job completions", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    async fn poll_loop(self) {
+        loop {
+            let job_ids: Vec<u64> = {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                if state.jobs.is_empty() {
+                    state.polling = false;
+                    return;
+                }
+                state.jobs.keys().copied().collect()
+            };
+
+            let polled = poll_jobs(&self.client, &job_ids).await;
+
+            self.record(polled);
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    fn record(&self, polled: Vec<(u64, Result<JobStatus, RcError>)>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        for (job_id, result) in polled {
+            if result.is_ok() {
+                if let Some(job) = state.jobs.get_mut(&job_id) {
+                    job.status_errors = 0;
+                }
+            }
+            let status = match result {
+                Ok(status) if !status.finished => continue,
+                Ok(status) => status,
+                Err(e) => {
+                    debug!("Error checking job status: {:?}", e);
+                    let Some(job) = state.jobs.get_mut(&job_id) else {
+                        continue;
+                    };
+                    job.status_errors += 1;
+                    if job.status_errors < MAX_STATUS_ERRORS {
+                        continue;
+                    }
+                    lost_job(&e.to_string())
+                }
+            };
+            self.complete(&mut state, job_id, status);
+        }
+    }
+
+    fn complete(&self, state: &mut TrackerState, job_id: u64, status: JobStatus) {
+        debug!("job_id {:?} finished", job_id);
+        if let Some(job) = state.jobs.remove(&job_id) {
+            for waiter in job.waiters {
+                let _ = waiter.send(status.clone());
+            }
+        }
+        // no subscribers is fine
+        let _ = self.events.send(JobEvent { job_id, status });
+    }
+}
+
+// Status of the jobs that may have finished. Jobs job/list reports as
+// running are left out, without job/list every job is checked.
+async fn poll_jobs(client: &RcClient, job_ids: &[u64]) -> Vec<(u64, Result<JobStatus, RcError>)> {
+    let running = match rclone::running_jobs(client).await {
+        Ok(running) => running,
+        Err(e) => {
+            debug!("job/list failed: {:?}", e);
+            None
+        }
+    };
+    let to_check = job_ids.iter().copied().filter(|job_id| {
+        !running
+            .as_ref()
+            .is_some_and(|running| running.contains(job_id))
+    });
+
+    futures::future::join_all(
+        to_check
+            .map(|job_id| async move { (job_id, rclone::check_job_status(client, job_id).await) }),
+    )
+    .await
+}
+
+fn lost_job(reason: &str) -> JobStatus {
+    JobStatus {
+        finished: true,
+        success: false,
+        error: Some(RcloneError::Network(format!(
+            "lost track of the job: {}",
+            reason
+        ))),
+        group: None,
+    }
+}
+
+#[cfg(test)]
+mod job_tracker_test {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_completion_reaches_waiters_and_subscribers() {
+        let tracker = JobTracker::default();
+        let mut completions = Box::pin(tracker.completions());
+
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = tracker.state.lock().unwrap();
+            state.jobs.entry(7).or_default().waiters.push(tx);
+            let status = JobStatus {
+                finished: true,
+                success: true,
+                error: None,
+                group: Some("vault".to_string()),
+            };
+            tracker.complete(&mut state, 7, status);
+            assert!(state.jobs.is_empty());
+        }
+
+        assert!(rx.await.unwrap().success);
+        let event = completions.next().await.unwrap();
+        assert_eq!(event.job_id, 7);
+        assert_eq!(event.status.group.as_deref(), Some("vault"));
+    }
+
+    #[test]
+    fn test_status_errors_reset_after_a_poll() {
+        let tracker = JobTracker::default();
+        tracker.state.lock().unwrap().jobs.entry(7).or_default();
+        let failed = || {
+            (
+                7,
+                Err(RcError::MissingField {
+                    command: "job/status".to_string(),
+                    field: "finished",
+                }),
+            )
+        };
+        let running = JobStatus {
+            finished: false,
+            success: false,
+            error: None,
+            group: None,
+        };
+
+        // scattered errors never add up to MAX_STATUS_ERRORS in a row
+        for _ in 0..3 {
+            let mut polled = vec![];
+            polled.resize_with(MAX_STATUS_ERRORS as usize - 1, failed);
+            tracker.record(polled);
+            tracker.record(vec![(7, Ok(running.clone()))]);
+        }
+        assert_eq!(tracker.state.lock().unwrap().jobs[&7].status_errors, 0);
+
+        let mut polled = vec![];
+        polled.resize_with(MAX_STATUS_ERRORS as usize, failed);
+        tracker.record(polled);
+        assert!(tracker.state.lock().unwrap().jobs.is_empty());
+    }
+}
@@ -0,0 +1,703 @@
+use chrono::{DateTime, Local};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::OnceLock;
+use tracing::debug;
+
+use crate::error::{RcError, RcloneError};
+use crate::operations::rclone::RC_PORT;
+
+// Typed client for the remote control API of the rclone daemon.
+// Every endpoint has a request struct naming its command and the
+// response it answers with, parameters keep their JSON types.
+
+// One client for every request so connections to the daemon are reused
+pub fn client() -> &'static Client {
+    static RC_CLIENT: OnceLock<Client> = OnceLock::new();
+    RC_CLIENT.get_or_init(Client::new)
+}
+
+pub trait RcCall: Serialize {
+    const COMMAND: &'static str;
+    type Response: DeserializeOwned;
+}
+
+// The rclone daemon calls go to, the one cl_sync starts on RC_PORT unless
+// another address is given, e.g. of a stand-in daemon in the tests.
+// Cloning is cheap, every clone shares the connections of client().
+#[derive(Debug, Clone)]
+pub struct RcClient {
+    base_url: String,
+}
+
+impl Default for RcClient {
+    fn default() -> Self {
+        Self::new(format!("http://localhost:{}", RC_PORT))
+    }
+}
+
+impl RcClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+        }
+    }
+
+    pub async fn call<C: RcCall>(&self, request: &C) -> Result<C::Response, RcError> {
+        call(&self.base_url, request).await
+    }
+}
+
+async fn call<C: RcCall>(base_url: &str, request: &C) -> Result<C::Response, RcError> {
+    let url = format!("{}/{}", base_url, C::COMMAND);
+    let response = client()
+        .post(url)
+        .json(request)
+        .send()
+        .await
+        .map_err(|source| RcError::Request {
+            command: C::COMMAND.to_string(),
+            source,
+        })?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(RcError::Status {
+            command: C::COMMAND.to_string(),
+            status: status.as_u16(),
+            error: decode_error(&body, status.as_u16()),
+        });
+    }
+
+    let response = response
+        .json::<C::Response>()
+        .await
+        .map_err(|source| RcError::Decode {
+            command: C::COMMAND.to_string(),
+            source,
+        })?;
+    Ok(response)
+}
+
+// Body rclone answers with when a call fails
+#[derive(Debug, Deserialize)]
+pub struct ErrorBody {
+    pub error: String,
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub status: u16,
+    #[serde(default)]
+    pub input: Option<Value>,
+}
+
+// Error of a failed call, falls back to the raw body and then to the
+// HTTP status for answers that aren't from rclone itself, e.g. a proxy
+fn decode_error(body: &str, status: u16) -> RcloneError {
+    let message = match serde_json::from_str::<ErrorBody>(body) {
+        Ok(body) => {
+            debug!("rclone {} failed with {:?}", body.path, body.input);
+            body.error
+        }
+        Err(_) if !body.trim().is_empty() => body.trim().to_string(),
+        Err(_) => status.to_string(),
+    };
+    RcloneError::from_status(status, &message)
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+// Options every command that runs as a job understands
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobOptions {
+    // start the job in the background and answer with its id
+    #[serde(rename = "_async", skip_serializing_if = "is_false")]
+    pub run_async: bool,
+
+    // stats group of the job, jobs of an entry share one
+    #[serde(rename = "_group", skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+
+    // rclone options only for this job, e.g. {"Transfers": 8}
+    #[serde(rename = "_config", skip_serializing_if = "Option::is_none")]
+    pub config: Option<Map<String, Value>>,
+
+    // filter rules only for this job, e.g. {"ExcludeRule": ["*.tmp"]}
+    #[serde(rename = "_filter", skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Map<String, Value>>,
+}
+
+impl JobOptions {
+    pub fn background(group: Option<&str>) -> Self {
+        Self {
+            run_async: true,
+            group: group.map(str::to_string),
+            ..Default::default()
+        }
+    }
+}
+
+// Answer of a command started with `_async`
+#[derive(Debug, Deserialize)]
+pub struct JobStarted {
+    #[serde(rename = "jobid")]
+    pub job_id: Option<u64>,
+}
+
+impl JobStarted {
+    // rclone always sends one for `_async` calls
+    pub fn job_id(&self, command: &str) -> Result<u64, RcError> {
+        self.job_id.ok_or_else(|| RcError::MissingField {
+            command: command.to_string(),
+            field: "jobid",
+        })
+    }
+}
+
+// Answer of commands that only report success
+#[derive(Debug, Deserialize)]
+pub struct Empty {}
+
+// Make dstFs identical to srcFs
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncRequest {
+    pub src_fs: String,
+    pub dst_fs: String,
+    #[serde(skip_serializing_if = "is_false")]
+    pub create_empty_src_dirs: bool,
+    #[serde(flatten)]
+    pub job: JobOptions,
+}
+
+impl RcCall for SyncRequest {
+    const COMMAND: &'static str = "sync/sync";
+    type Response = JobStarted;
+}
+
+// Copy srcFs to dstFs without deleting anything on dstFs
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyRequest {
+    pub src_fs: String,
+    pub dst_fs: String,
+    #[serde(skip_serializing_if = "is_false")]
+    pub create_empty_src_dirs: bool,
+    #[serde(flatten)]
+    pub job: JobOptions,
+}
+
+impl RcCall for CopyRequest {
+    const COMMAND: &'static str = "sync/copy";
+    type Response = JobStarted;
+}
+
+// Move srcFs to dstFs
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveRequest {
+    pub src_fs: String,
+    pub dst_fs: String,
+    #[serde(skip_serializing_if = "is_false")]
+    pub create_empty_src_dirs: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    pub delete_empty_src_dirs: bool,
+    #[serde(flatten)]
+    pub job: JobOptions,
+}
+
+impl RcCall for MoveRequest {
+    const COMMAND: &'static str = "sync/move";
+    type Response = JobStarted;
+}
+
+// Copy a single file, dstFile may rename it
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyFileRequest {
+    pub src_fs: String,
+    pub src_file: String,
+    pub dst_fs: String,
+    pub dst_file: String,
+    #[serde(flatten)]
+    pub job: JobOptions,
+}
+
+impl RcCall for CopyFileRequest {
+    const COMMAND: &'static str = "operations/copyfile";
+    type Response = JobStarted;
+}
+
+// Move a single file, dstFile may rename it
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveFileRequest {
+    pub src_fs: String,
+    pub src_file: String,
+    pub dst_fs: String,
+    pub dst_file: String,
+    #[serde(flatten)]
+    pub job: JobOptions,
+}
+
+impl RcCall for MoveFileRequest {
+    const COMMAND: &'static str = "operations/movefile";
+    type Response = JobStarted;
+}
+
+// Compare srcFs with dstFs by size and hash
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckRequest {
+    pub src_fs: String,
+    pub dst_fs: String,
+    // only report files missing on dstFs, not the ones missing on srcFs
+    #[serde(skip_serializing_if = "is_false")]
+    pub one_way: bool,
+    // compare the contents instead of the hashes
+    #[serde(skip_serializing_if = "is_false")]
+    pub download: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CheckResponse {
+    pub success: bool,
+    pub status: String,
+    pub hash_type: Option<String>,
+    pub missing_on_src: Vec<String>,
+    pub missing_on_dst: Vec<String>,
+    #[serde(rename = "match")]
+    pub matching: Vec<String>,
+    pub differ: Vec<String>,
+    pub error: Vec<String>,
+}
+
+impl RcCall for CheckRequest {
+    const COMMAND: &'static str = "operations/check";
+    type Response = CheckResponse;
+}
+
+// A single file or dir, item is None when it doesn't exist
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatRequest {
+    pub fs: String,
+    pub remote: String,
+    pub opt: StatOptions,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatOptions {
+    #[serde(skip_serializing_if = "is_false")]
+    pub show_hash: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct StatResponse {
+    pub item: Option<StatItem>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct StatItem {
+    pub path: String,
+    pub name: String,
+    // -1 when the remote doesn't know it
+    pub size: i64,
+    pub mod_time: Option<DateTime<Local>>,
+    pub is_dir: bool,
+    // hash type -> hash, only the types the remote supports
+    pub hashes: hashbrown::HashMap<String, String>,
+}
+
+impl RcCall for StatRequest {
+    const COMMAND: &'static str = "operations/stat";
+    type Response = StatResponse;
+}
+
+// Entries of a dir
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ListRequest {
+    pub fs: String,
+    pub remote: String,
+    pub opt: ListOptions,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListOptions {
+    #[serde(skip_serializing_if = "is_false")]
+    pub dirs_only: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ListResponse {
+    pub list: Vec<StatItem>,
+}
+
+impl RcCall for ListRequest {
+    const COMMAND: &'static str = "operations/list";
+    type Response = ListResponse;
+}
+
+// Delete a dir and everything in it
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PurgeRequest {
+    pub fs: String,
+    pub remote: String,
+}
+
+impl RcCall for PurgeRequest {
+    const COMMAND: &'static str = "operations/purge";
+    type Response = Empty;
+}
+
+// Space used and left on a remote, fs is e.g. "remote:"
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AboutRequest {
+    pub fs: String,
+}
+
+// Remotes only report the values their API knows, in bytes
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AboutResponse {
+    pub total: Option<u64>,
+    pub used: Option<u64>,
+    pub trashed: Option<u64>,
+    pub other: Option<u64>,
+    pub free: Option<u64>,
+    pub objects: Option<u64>,
+}
+
+impl RcCall for AboutRequest {
+    const COMMAND: &'static str = "operations/about";
+    type Response = AboutResponse;
+}
+
+// Total size of the files in fs, e.g. "remote:path/dir"
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SizeRequest {
+    pub fs: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SizeResponse {
+    pub count: u64,
+    pub bytes: u64,
+    // files whose size the remote doesn't know
+    pub sizeless: u64,
+}
+
+impl RcCall for SizeRequest {
+    const COMMAND: &'static str = "operations/size";
+    type Response = SizeResponse;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobStatusRequest {
+    #[serde(rename = "jobid")]
+    pub job_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatusResponse {
+    pub id: u64,
+    pub finished: bool,
+    #[serde(default)]
+    pub success: bool,
+    // empty while the job runs and when it succeeded
+    #[serde(default)]
+    pub error: String,
+    #[serde(default)]
+    pub group: String,
+    #[serde(default)]
+    pub duration: f64,
+    pub start_time: Option<DateTime<Local>>,
+    pub end_time: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub output: Option<Value>,
+}
+
+impl RcCall for JobStatusRequest {
+    const COMMAND: &'static str = "job/status";
+    type Response = JobStatusResponse;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobStopRequest {
+    #[serde(rename = "jobid")]
+    pub job_id: u64,
+}
+
+impl RcCall for JobStopRequest {
+    const COMMAND: &'static str = "job/stop";
+    type Response = Empty;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobListRequest {}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct JobListResponse {
+    #[serde(rename = "jobids")]
+    pub job_ids: Vec<u64>,
+    // only sent by rclone 1.64 and newer
+    pub running_ids: Option<Vec<u64>>,
+    pub finished_ids: Option<Vec<u64>>,
+}
+
+impl RcCall for JobListRequest {
+    const COMMAND: &'static str = "job/list";
+    type Response = JobListResponse;
+}
+
+// Transfer stats of every job, or only of one group
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CoreStatsRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CoreStatsResponse {
+    pub bytes: u64,
+    pub checks: u64,
+    pub deletes: u64,
+    pub elapsed_time: f64,
+    pub errors: u64,
+    pub eta: Option<f64>,
+    pub fatal_error: bool,
+    pub retry_error: bool,
+    pub last_error: Option<String>,
+    pub speed: f64,
+    pub total_bytes: u64,
+    pub total_checks: u64,
+    pub total_transfers: u64,
+    pub transfer_time: f64,
+    pub transfers: u64,
+}
+
+impl RcCall for CoreStatsRequest {
+    const COMMAND: &'static str = "core/stats";
+    type Response = CoreStatsResponse;
+}
+
+// Every option of the daemon by block, the "main" block holds
+// the names `_config` takes, a backend's block the options of it
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OptionsGetRequest {}
+
+impl RcCall for OptionsGetRequest {
+    const COMMAND: &'static str = "options/get";
+    type Response = Map<String, Value>;
+}
+
+// Settings of a remote in the rclone config, its backend under "type"
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfigGetRequest {
+    pub name: String,
+}
+
+impl RcCall for ConfigGetRequest {
+    const COMMAND: &'static str = "config/get";
+    type Response = Map<String, Value>;
+}
+
+// Set the bandwidth limit of the daemon, or only read it without a rate.
+// Takes a rate ("512k", "off") or a timetable ("08:00,512k 23:00,off").
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CoreBwLimitRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CoreBwLimitResponse {
+    // -1 when there is no limit
+    pub bytes_per_second: i64,
+    pub bytes_per_second_tx: i64,
+    pub bytes_per_second_rx: i64,
+    pub rate: String,
+}
+
+impl RcCall for CoreBwLimitRequest {
+    const COMMAND: &'static str = "core/bwlimit";
+    type Response = CoreBwLimitResponse;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MountRequest {
+    pub fs: String,
+    pub mount_point: String,
+    // "mount", "cmount" or "mount2", rclone picks one when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mount_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mount_opt: Option<Map<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vfs_opt: Option<Map<String, Value>>,
+    #[serde(flatten)]
+    pub job: JobOptions,
+}
+
+impl RcCall for MountRequest {
+    const COMMAND: &'static str = "mount/mount";
+    type Response = JobStarted;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnmountRequest {
+    pub mount_point: String,
+}
+
+impl RcCall for UnmountRequest {
+    const COMMAND: &'static str = "mount/unmount";
+    type Response = Empty;
+}
+
+// Remotes mounted by this daemon
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ListMountsRequest {}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MountPoint {
+    pub fs: String,
+    pub mount_point: String,
+    #[serde(default)]
+    pub mounted_on: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ListMountsResponse {
+    pub mount_points: Vec<MountPoint>,
+}
+
+impl RcCall for ListMountsRequest {
+    const COMMAND: &'static str = "mount/listmounts";
+    type Response = ListMountsResponse;
+}
+
+// Unmount everything this daemon mounted
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UnmountAllRequest {}
+
+impl RcCall for UnmountAllRequest {
+    const COMMAND: &'static str = "mount/unmountall";
+    type Response = Empty;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ListRemotesRequest {}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ListRemotesResponse {
+    pub remotes: Vec<String>,
+}
+
+impl RcCall for ListRemotesRequest {
+    const COMMAND: &'static str = "config/listremotes";
+    type Response = ListRemotesResponse;
+}
+
+#[cfg(test)]
+mod rc_test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_request_keeps_json_types() {
+        let mut job = JobOptions::background(Some("vault"));
+        job.config = Some(json!({ "Transfers": 8 }).as_object().unwrap().clone());
+        let request = SyncRequest {
+            src_fs: "/home/user/vault".to_string(),
+            dst_fs: "dge:Vault".to_string(),
+            create_empty_src_dirs: true,
+            job,
+        };
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({
+                "srcFs": "/home/user/vault",
+                "dstFs": "dge:Vault",
+                "createEmptySrcDirs": true,
+                "_async": true,
+                "_group": "vault",
+                "_config": { "Transfers": 8 },
+            })
+        );
+
+        let request = CopyFileRequest {
+            src_fs: "/home/user".to_string(),
+            src_file: "pw.kdbx".to_string(),
+            dst_fs: "dge:".to_string(),
+            dst_file: "Keys/pw.kdbx".to_string(),
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&request).unwrap();
+        assert!(value.get("_async").is_none());
+        assert!(value.get("_group").is_none());
+    }
+
+    #[test]
+    fn test_decode_responses() {
+        let status: JobStatusResponse = serde_json::from_value(json!({
+            "id": 70000,
+            "finished": true,
+            "success": false,
+            "error": "directory not found",
+            "group": "vault",
+            "duration": 1.5,
+            "startTime": "2024-11-20T10:00:00.000000000+01:00",
+            "endTime": "2024-11-20T10:00:01.500000000+01:00",
+            "output": {},
+        }))
+        .unwrap();
+        assert_eq!(status.id, 70000);
+        assert_eq!(status.error, "directory not found");
+
+        let list: JobListResponse = serde_json::from_value(json!({ "jobids": [1, 2] })).unwrap();
+        assert_eq!(list.job_ids, vec![1, 2]);
+        assert!(list.running_ids.is_none());
+
+        let mounts: ListMountsResponse = serde_json::from_value(json!({
+            "mountPoints": [{
+                "Fs": "dge",
+                "MountPoint": "/home/user/dge",
+                "MountedOn": "2024-11-20T10:00:00.000000000+01:00",
+            }]
+        }))
+        .unwrap();
+        assert_eq!(mounts.mount_points[0].mount_point, "/home/user/dge");
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let body = r#"{"error":"didn't find section in config file","input":{"fs":"nope:"},"path":"operations/about","status":500}"#;
+        assert!(matches!(decode_error(body, 500), RcloneError::NotFound(_)));
+        assert_eq!(
+            decode_error("bad gateway\n", 502),
+            RcloneError::Other("bad gateway".to_string())
+        );
+        assert_eq!(decode_error("", 502), RcloneError::Other("502".to_string()));
+        assert_eq!(decode_error("", 401), RcloneError::Auth("401".to_string()));
+    }
+}
@@ -1,39 +1,41 @@
-use chrono::{DateTime, Local};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use hashbrown::HashMap;
 use tokio::process::{Child, Command};
 use tracing::debug;
 
+use crate::error::{DaemonError, RcError, RcloneError};
+use crate::operations::rc::{self, RcCall};
 use crate::operations::toml;
 
+pub const RC_PORT: u16 = 5574;
+
 pub struct RcloneServer {
     pub process: Option<Child>,
 }
 
 impl RcloneServer {
-    pub async fn start() -> Self {
+    pub async fn start() -> Result<Self, DaemonError> {
         let process = Command::new("rclone")
             .arg("rcd")
             .arg("--rc-no-auth")
-            .arg("--rc-addr=:5574")
+            .arg(format!("--rc-addr=:{}", RC_PORT))
             .arg("--rc-enable-metrics")
             .spawn()
-            .expect("Failed to start rclone daemon");
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => DaemonError::NotInstalled,
+                _ => DaemonError::Start(e),
+            })?;
 
-        println!("rclone server started on port 5574");
-        Self {
+        println!("rclone server started on port {}", RC_PORT);
+        Ok(Self {
             process: Some(process),
-        }
+        })
     }
 
     pub async fn is_running() -> bool {
-        let client = Client::new();
-        let url = "http://localhost:5574/metrics";
+        let client = rc::client();
+        let url = format!("http://localhost:{}/metrics", RC_PORT);
 
-        match client.get(url).send().await {
-            Ok(response) if response.status().is_success() => true,
-            _ => false,
-        }
+        matches!(client.get(url).send().await, Ok(response) if response.status().is_success())
     }
 
     pub async fn stop(&mut self) {
@@ -46,146 +48,314 @@ impl RcloneServer {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RcloneResponse {
-    #[serde(rename = "jobid")]
-    job_id: Option<u16>,
-    duration: Option<f64>,
-    error: Option<String>,
-    finished: Option<bool>,
-    group: Option<String>,
-    id: Option<u16>,
-    success: Option<bool>,
-
-    #[serde(rename = "endTime")]
-    end_time: Option<DateTime<Local>>,
+// group tags the job with `_group` so its status and stats can be told apart
+pub async fn sync_sync(
+    client: &rc::RcClient,
+    from: String,
+    upload_to: String,
+    group: Option<&str>,
+) -> Result<u64, RcError> {
+    sync_dir(
+        client,
+        toml::UploadMode::Sync,
+        from,
+        upload_to,
+        rc::JobOptions::background(group),
+    )
+    .await
+}
 
-    #[serde(rename = "startTime")]
-    start_time: Option<DateTime<Local>>,
+// Upload a dir with sync/sync, sync/copy or sync/move, job should run `_async`.
+// A snapshot goes to a new dir so copying it is enough.
+pub async fn sync_dir(
+    client: &rc::RcClient,
+    mode: toml::UploadMode,
+    from: String,
+    upload_to: String,
+    job: rc::JobOptions,
+) -> Result<u64, RcError> {
+    match mode {
+        toml::UploadMode::Sync => {
+            let request = rc::SyncRequest {
+                src_fs: from,
+                dst_fs: upload_to,
+                create_empty_src_dirs: true,
+                job,
+            };
+            debug!("params : {:?}", request);
+            client
+                .call(&request)
+                .await?
+                .job_id(rc::SyncRequest::COMMAND)
+        }
+        toml::UploadMode::Copy | toml::UploadMode::Snapshot => {
+            let request = rc::CopyRequest {
+                src_fs: from,
+                dst_fs: upload_to,
+                create_empty_src_dirs: true,
+                job,
+            };
+            debug!("params : {:?}", request);
+            client
+                .call(&request)
+                .await?
+                .job_id(rc::CopyRequest::COMMAND)
+        }
+        toml::UploadMode::Move => {
+            let request = rc::MoveRequest {
+                src_fs: from,
+                dst_fs: upload_to,
+                create_empty_src_dirs: true,
+                delete_empty_src_dirs: true,
+                job,
+            };
+            debug!("params : {:?}", request);
+            client
+                .call(&request)
+                .await?
+                .job_id(rc::MoveRequest::COMMAND)
+        }
+    }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RcloneRquest {
-    pub command: String,
-    pub params: hashbrown::HashMap<String, String>,
+// Status of an rclone job, error is set when a finished job failed
+#[derive(Debug, Clone)]
+pub struct JobStatus {
+    pub finished: bool,
+    pub success: bool,
+    pub error: Option<RcloneError>,
+    pub group: Option<String>,
+}
 
-    #[serde(rename = "jobid")]
-    pub job_id: Option<u16>,
+pub async fn check_job_status(client: &rc::RcClient, job_id: u64) -> Result<JobStatus, RcError> {
+    let response = client.call(&rc::JobStatusRequest { job_id }).await?;
+    debug!("check job status \n{:?}", response);
+
+    Ok(JobStatus {
+        finished: response.finished,
+        success: response.success,
+        error: Some(response.error)
+            .filter(|error| !error.is_empty())
+            .map(|error| RcloneError::from_message(&error)),
+        group: Some(response.group).filter(|group| !group.is_empty()),
+    })
+}
 
-    pub finished: Option<bool>,
+// Ids of the jobs rclone is still running, None when
+// this rclone version doesn't report them in job/list
+pub async fn running_jobs(client: &rc::RcClient) -> Result<Option<Vec<u64>>, RcError> {
+    let response = client.call(&rc::JobListRequest {}).await?;
+    Ok(response.running_ids)
 }
 
-impl RcloneRquest {
-    pub async fn post(&mut self) -> Result<RcloneResponse, reqwest::Error> {
-        let client = Client::new();
-        let command = format!("http://localhost:5574/{}", self.command);
+// Ask rclone to cancel a running job
+pub async fn stop_job(client: &rc::RcClient, job_id: u64) -> Result<(), RcError> {
+    client.call(&rc::JobStopRequest { job_id }).await?;
+    Ok(())
+}
 
-        let response = client
-            .post(command)
-            .json(&self.params)
-            .send()
-            .await?
-            .json::<RcloneResponse>()
-            .await?;
-        if response.job_id.is_some() {
-            self.job_id = Some(response.job_id.unwrap());
-        }
-        if response.finished.is_some() {
-            self.finished = Some(response.finished.unwrap());
-        }
-        println!("post response: \n{:?}", response);
-        Ok(response)
+pub async fn mount_remote(
+    client: &rc::RcClient,
+    remote: &toml::CloudProviders,
+) -> Result<u64, RcError> {
+    let mut job = rc::JobOptions::background(None);
+    if !remote.rclone_options.is_empty() {
+        job.config = Some(remote.rclone_options.clone());
     }
+    let request = rc::MountRequest {
+        fs: remote.cloud_name.to_string(),
+        mount_point: remote.dir.to_string(),
+        job,
+        ..Default::default()
+    };
+    debug!("{:?} ", request);
+    client
+        .call(&request)
+        .await?
+        .job_id(rc::MountRequest::COMMAND)
 }
 
-pub async fn sync_sync(from: String, upload_to: String) -> anyhow::Result<RcloneRquest> {
-    let mut params = hashbrown::HashMap::new();
-    params.insert("srcFs".to_string(), from);
-    params.insert("dstFs".to_string(), upload_to);
-
-    params.insert("createEmptySrcDirs".to_string(), "true".to_string());
-    params.insert("_async".to_string(), "true".to_string());
-    println!("params : {:?}", params);
+// Space used and left on the remote of fs
+pub async fn about(client: &rc::RcClient, fs: &str) -> Result<rc::AboutResponse, RcError> {
+    let request = rc::AboutRequest { fs: fs.to_string() };
+    client.call(&request).await
+}
 
-    let mut rclone_rquest = RcloneRquest {
-        command: "sync/sync".to_string(),
-        params,
-        job_id: None,
-        finished: None,
+// Bytes of the files in the dir remote, e.g. "dge:desk/Vault"
+pub async fn size(client: &rc::RcClient, remote: &str) -> Result<u64, RcError> {
+    let request = rc::SizeRequest {
+        fs: remote.to_string(),
     };
-    rclone_rquest.post().await?;
-
-    Ok(rclone_rquest)
+    Ok(client.call(&request).await?.bytes)
 }
 
-pub async fn check_job_status(job_id: u16) -> anyhow::Result<bool> {
-    let mut params = hashbrown::HashMap::new();
-    params.insert("jobid".to_string(), job_id.to_string());
+// Names of the options of every block, "main" holds the ones
+// `_config` accepts, "drive" the ones of the drive backend
+pub async fn option_names(client: &rc::RcClient) -> Result<HashMap<String, Vec<String>>, RcError> {
+    let names: HashMap<String, Vec<String>> = client
+        .call(&rc::OptionsGetRequest {})
+        .await?
+        .into_iter()
+        .filter_map(|(block, options)| match options {
+            serde_json::Value::Object(options) => Some((block, options.keys().cloned().collect())),
+            _ => None,
+        })
+        .collect();
+    if !names.contains_key("main") {
+        return Err(RcError::MissingField {
+            command: rc::OptionsGetRequest::COMMAND.to_string(),
+            field: "main",
+        });
+    }
+    Ok(names)
+}
 
-    //params.insert("_async".to_string(), "true".to_string());
+// Backend of a remote in the rclone config, e.g. "drive"
+pub async fn remote_type(client: &rc::RcClient, remote: &str) -> Result<String, RcError> {
+    let request = rc::ConfigGetRequest {
+        name: remote.to_string(),
+    };
+    match client.call(&request).await?.remove("type") {
+        Some(serde_json::Value::String(backend)) => Ok(backend),
+        _ => Err(RcError::MissingField {
+            command: rc::ConfigGetRequest::COMMAND.to_string(),
+            field: "type",
+        }),
+    }
+}
 
-    let mut rclone_rquest = RcloneRquest {
-        command: "job/status".to_string(),
-        params,
-        job_id: None,
-        finished: None,
+// Current bandwidth limit of the daemon, changed first when a rate is given
+pub async fn bwlimit(client: &rc::RcClient, rate: Option<&str>) -> Result<String, RcError> {
+    let request = rc::CoreBwLimitRequest {
+        rate: rate.map(str::to_string),
     };
-    rclone_rquest.post().await?;
-    println!("cehck job stat \n{:?}", rclone_rquest);
+    Ok(client.call(&request).await?.rate)
+}
 
-    Ok(rclone_rquest.finished.unwrap())
+// Mount points of every remote the daemon has mounted
+pub async fn list_mounts(client: &rc::RcClient) -> Result<Vec<String>, RcError> {
+    let response = client.call(&rc::ListMountsRequest {}).await?;
+    Ok(response
+        .mount_points
+        .into_iter()
+        .map(|mount| mount.mount_point)
+        .collect())
 }
 
-pub async fn mount_remote(remote: &toml::CloudProviders) -> anyhow::Result<RcloneRquest> {
-    let mut params = hashbrown::HashMap::new();
-    //params.insert("fs".to_string(), "dge:".to_string());
-    params.insert("fs".to_string(), remote.cloud_name.to_string());
+pub async fn unmount(client: &rc::RcClient, mount_point: &str) -> Result<(), RcError> {
+    println!("Dismounting: {}", mount_point);
+    let request = rc::UnmountRequest {
+        mount_point: mount_point.to_string(),
+    };
+    client.call(&request).await?;
+    println!("Successfully dismounted: {}", mount_point);
+    Ok(())
+}
+
+pub async fn unmount_all(client: &rc::RcClient) -> Result<(), RcError> {
+    println!("Dismounting every remote");
+    client.call(&rc::UnmountAllRequest {}).await?;
+    Ok(())
+}
 
-    params.insert("mountPoint".to_string(), remote.dir.to_string());
-    params.insert("_async".to_string(), "true".to_string());
+// Size and hashes of a file, None when it doesn't exist
+pub async fn stat(
+    client: &rc::RcClient,
+    fs: &str,
+    remote: &str,
+) -> Result<Option<rc::StatItem>, RcError> {
+    let request = rc::StatRequest {
+        fs: fs.to_string(),
+        remote: remote.to_string(),
+        opt: rc::StatOptions { show_hash: true },
+    };
+    Ok(client.call(&request).await?.item)
+}
 
-    let mut rclone_rquest = RcloneRquest {
-        command: "mount/mount".to_string(),
-        params,
-        job_id: None,
-        finished: None,
+// Dirs inside remote
+pub async fn list_dirs(
+    client: &rc::RcClient,
+    fs: &str,
+    remote: &str,
+) -> Result<Vec<rc::StatItem>, RcError> {
+    let request = rc::ListRequest {
+        fs: fs.to_string(),
+        remote: remote.to_string(),
+        opt: rc::ListOptions { dirs_only: true },
     };
-    rclone_rquest.post().await?;
-    debug!("{:?} ", rclone_rquest);
+    Ok(client.call(&request).await?.list)
+}
 
-    Ok(rclone_rquest)
+pub async fn purge(client: &rc::RcClient, fs: &str, remote: &str) -> Result<(), RcError> {
+    let request = rc::PurgeRequest {
+        fs: fs.to_string(),
+        remote: remote.to_string(),
+    };
+    client.call(&request).await?;
+    Ok(())
 }
 
+// Copy srcFs/srcFile to dstFs/dstFile, e.g. "/home/user" "pw.kdbx" to "dge:" "Keys/pw.kdbx"
 pub async fn copyfile(
-    local_dir: String,
-    file_name: String,
-    remote_name: String,
-    remote_dst: String,
-) -> anyhow::Result<RcloneRquest> {
-    //"srcFs": "/home/user/",
-    //"srcFile": "file.txt",
-    //"dstFs": "remote:",
-    //"dstFile": "file.txt"
-
-    let mut params = hashbrown::HashMap::new();
-    params.insert("srcFs".to_string(), local_dir);
-    params.insert("srcFile".to_string(), file_name);
-    params.insert("dstFs".to_string(), remote_name);
-    params.insert("dstFile".to_string(), remote_dst);
-
-    params.insert("_async".to_string(), "true".to_string());
-    println!("params : {:?}", params);
-
-    let mut rclone_rquest = RcloneRquest {
-        command: "operations/copyfile".to_string(),
-        params,
-        job_id: None,
-        finished: None,
-    };
-    rclone_rquest.post().await?;
+    client: &rc::RcClient,
+    src_fs: String,
+    src_file: String,
+    dst_fs: String,
+    dst_file: String,
+    group: Option<&str>,
+) -> Result<u64, RcError> {
+    transfer_file(
+        client,
+        toml::UploadMode::Copy,
+        src_fs,
+        src_file,
+        dst_fs,
+        dst_file,
+        rc::JobOptions::background(group),
+    )
+    .await
+}
 
-    Ok(rclone_rquest)
+// Upload a single file, sync, copy and snapshot copy it, move deletes it afterwards
+pub async fn transfer_file(
+    client: &rc::RcClient,
+    mode: toml::UploadMode,
+    src_fs: String,
+    src_file: String,
+    dst_fs: String,
+    dst_file: String,
+    job: rc::JobOptions,
+) -> Result<u64, RcError> {
+    match mode {
+        toml::UploadMode::Sync | toml::UploadMode::Copy | toml::UploadMode::Snapshot => {
+            let request = rc::CopyFileRequest {
+                src_fs,
+                src_file,
+                dst_fs,
+                dst_file,
+                job,
+            };
+            debug!("params : {:?}", request);
+            client
+                .call(&request)
+                .await?
+                .job_id(rc::CopyFileRequest::COMMAND)
+        }
+        toml::UploadMode::Move => {
+            let request = rc::MoveFileRequest {
+                src_fs,
+                src_file,
+                dst_fs,
+                dst_file,
+                job,
+            };
+            debug!("params : {:?}", request);
+            client
+                .call(&request)
+                .await?
+                .job_id(rc::MoveFileRequest::COMMAND)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -196,7 +366,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_rclone_server_start_stop() {
-        let mut rclone_server = RcloneServer::start().await;
+        let mut rclone_server = RcloneServer::start().await.unwrap();
 
         // Simulate doing some work
         let start_time = Instant::now();
@@ -221,7 +391,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_rclone_sync_sync_stop() {
-        let mut rclone_server = RcloneServer::start().await;
+        let mut rclone_server = RcloneServer::start().await.unwrap();
         //sleep(Duration::from_secs(5)).await; // Adjust if needed
 
         while !RcloneServer::is_running().await {
@@ -234,8 +404,10 @@ mod tests {
         //}
 
         if let Err(e) = sync_sync(
+            &rc::RcClient::default(),
             "/home/user/Documents/dir/".to_string(),
             "remote:dir".to_string(),
+            None,
         )
         .await
         {
@@ -3,6 +3,7 @@ use async_recursion::async_recursion;
 use chrono::{DateTime, Local};
 use home::home_dir;
 use indoc::indoc;
+use sha2::{Digest, Sha256};
 use std::path::Path;
 use std::path::PathBuf;
 use tokio::fs;
@@ -10,6 +11,8 @@ use tracing::debug;
 
 use anyhow::Context;
 
+use crate::error::ClSyncError;
+
 use std::process::ExitStatus;
 use std::process::Stdio;
 
@@ -66,10 +69,17 @@ pub async fn config_dir_exists() -> Result<()> {
     Ok(())
 }
 
-pub async fn create_toml_file() -> Result<()> {
-    // Get home directory safely
+// Path of upload.toml, CL_SYNC_CONFIG overrides the default in ~/.config/cl_sync
+pub fn toml_file_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("CL_SYNC_CONFIG") {
+        return PathBuf::from(path);
+    }
     let home_path = home_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
-    let config_path = home_path.join(".config/cl_sync/upload.toml");
+    home_path.join(".config/cl_sync/upload.toml")
+}
+
+pub async fn create_toml_file() -> Result<()> {
+    let config_path = toml_file_path();
 
     let default_content = get_default_toml();
     fs::write(&config_path, default_content).await?;
@@ -77,9 +87,82 @@ pub async fn create_toml_file() -> Result<()> {
     Ok(())
 }
 
+// Absolute path with symlinks resolved, the path as given when it doesn't exist
+pub async fn canonical_path(path: &str) -> String {
+    match fs::canonicalize(path).await {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+// Hash of the paths, sizes and modified times under path.
+// Changes whenever a file is added, removed or modified.
+pub async fn manifest_hash(path: &Path) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        hash_manifest(&path, &path, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+// Bytes of every file under path, what uploading it transfers at most
+pub async fn tree_size(path: &Path) -> Result<u64> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || sum_sizes(&path)).await?
+}
+
+fn sum_sizes(path: &Path) -> Result<u64> {
+    let fs_error = |source| ClSyncError::Filesystem {
+        path: path.to_path_buf(),
+        source,
+    };
+    let metadata = std::fs::symlink_metadata(path).map_err(fs_error)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(path).map_err(fs_error)? {
+        size += sum_sizes(&entry.map_err(fs_error)?.path())?;
+    }
+    Ok(size)
+}
+
+fn hash_manifest(root: &Path, path: &Path, hasher: &mut Sha256) -> Result<()> {
+    let fs_error = |source| ClSyncError::Filesystem {
+        path: path.to_path_buf(),
+        source,
+    };
+    let metadata = std::fs::symlink_metadata(path).map_err(fs_error)?;
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    hasher.update(relative.to_string_lossy().as_bytes());
+    hasher.update([0]);
+
+    if metadata.is_dir() {
+        let mut entries = std::fs::read_dir(path)
+            .map_err(fs_error)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<PathBuf>>>()
+            .map_err(fs_error)?;
+        entries.sort();
+        for entry in entries {
+            hash_manifest(root, &entry, hasher)?;
+        }
+    } else {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_nanos());
+        hasher.update(metadata.len().to_le_bytes());
+        hasher.update(modified.to_le_bytes());
+    }
+    Ok(())
+}
+
 pub async fn to_epoch(modified: DateTime<Local>) -> i64 {
-    let datetime: DateTime<Local> = modified.into();
-    datetime.timestamp()
+    modified.timestamp()
 }
 
 pub async fn fusermount(cloud_dir: &str) -> Result<ExitStatus> {
@@ -143,8 +226,36 @@ fn get_default_toml() -> String {
   file_or_dir_path = "/home/user/Desktop/Text File (4).txt"
 #   based on cloud_providers section bellow add one or more cloud_name 's
   upload_to_clouds = [ "dge", "ode_rcl" ]
-#   cloud dir to upload to
+#   optional mode of the uploads, "sync" (default), "copy", "move" or "snapshot"
+#   which uploads into a new dir named after the date and time every time.
+#   "move" is only allowed when the entry has a single cloud.
+  # mode = "snapshot"
+#   optional snapshots to keep with mode = "snapshot", all of them by default
+  # retention = {{ keep_last = 3, keep_daily = 7, keep_weekly = 4, keep_monthly = 12 }}
+#   optional, upload a dir as one tar.zst file, for dirs with lots of small files.
+#   It respects the filters of each cloud and is only uploaded when its content changed.
+  # archive = {{ name = "OBvault.tar.zst", level = 3 }}
+#   or tables with settings for one cloud, mode is the mode of the entry by default
+  # upload_to_clouds = [
+  #   "dge",
+  #   {{ cloud = "ode_rcl", dir = "Archive/OBvault", mode = "copy", filters = ["- *.tmp"], bwlimit_file = "1M" }},
+  # ]
+#   cloud dir to upload to, under the paste_to_dir of each cloud,
+#   the dir of a cloud's table is used instead on that cloud
   upload_to_cloud_dir = "OBvault"
+#   optional name a single file gets on the clouds, defaults to its local name
+  # remote_file_name = "notes.txt"
+#   optional schedule for `cl_sync daemon`, an interval or a cron expression
+  # schedule = "10m"
+  # schedule = "0 3 * * *"
+#   optional retries of a failed upload, the backoff doubles after every retry
+  # retries = 2
+  # retry_backoff = "10s"
+#   optional limit on how long uploading this entry may take
+  # timeout = "30m"
+#   optional rclone settings for the jobs of this entry, named like in
+#   `rclone rc options/get`, they win over the rclone_options of the cloud
+  # rclone_options = {{ Transfers = 8, CheckSum = true }}
 #   optional Veracrypt container
   # veracrypt_mount_dir = "/home/user/Downloads/text-master"
   # veracrypt_file_name = "text-master"
@@ -154,7 +265,24 @@ fn get_default_toml() -> String {
 # dir = "cache.bin"
 dir = "{}/cache.bin" 
 
+[watch]
+# seconds an entry has to stay unchanged before `cl_sync watch` syncs it
+quiet_period_secs = 5
+
+[sync]
+# optional limit on how long a whole `cl_sync --sync` run may take
+# timeout = "2h"
+# optional upload limit, also used by watch and daemon mode, a rate or a timetable
+# change it while cl_sync runs with `cl_sync bwlimit <RATE>`
+# rclone has one limit for all its transfers, so a cloud can't get a bwlimit of its
+# own, bwlimit_file of a cloud limits each of its transfers instead
+# bwlimit = "08:00,512k 23:00,off"
+# what happens when a cloud has less space left than an entry needs:
+# "warn" (default), "abort" to skip that cloud or "off"
+# quota_check = "warn"
+
 # modify
+# paste_to_dir: where uploads to the cloud go, upload_to_cloud_dir is relative to it
 [cloud_providers]
   [cloud_providers.dg]
   cloud_name = "dg"
@@ -170,6 +298,14 @@ dir = "{}/cache.bin"
   cloud_name = "dge"
   dir = "/home/user/Documents/cloud/dge/" 
   paste_to_dir = "dge:desk/"
+  # retries = 5
+  # retry_backoff = "30s"
+  # limit of each single file transfer to this cloud, files uploaded
+  # at the same time each get it, [sync] bwlimit caps them all together
+  # bwlimit_file = "1M"
+  # rclone_options = {{ UseListR = true }}
+  # settings of the backend of the remote, named like its flags (--drive-use-trash)
+  # backend_options = {{ drive_use_trash = false, drive_chunk_size = "64M" }}
 
   [cloud_providers.ode_rcl]
   cloud_name = "ode_rcl"
@@ -180,6 +316,23 @@ dir = "{}/cache.bin"
     )
 }
 
+#[tokio::test]
+async fn test_manifest_hash() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("cl_sync_manifest_{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub")).await?;
+    fs::write(dir.join("sub/a.txt"), "a").await?;
+
+    let before = manifest_hash(&dir).await?;
+    assert_eq!(before, manifest_hash(&dir).await?);
+
+    fs::write(dir.join("sub/b.txt"), "b").await?;
+    let after = manifest_hash(&dir).await?;
+    assert_ne!(before, after);
+
+    fs::remove_dir_all(&dir).await?;
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_read_dir_content() {
     let entries = Path::new("/home/dev/Documents/palyOB/OBvault/");
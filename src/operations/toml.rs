@@ -1,18 +1,19 @@
 use crate::error;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use hashbrown::HashMap;
 use home::home_dir;
 use serde_derive::{Deserialize, Serialize};
 use std::io::ErrorKind;
 use std::path::PathBuf;
+use std::time::Duration;
 use tokio::fs;
 use toml;
 use tracing::debug;
 
 use super::sys_ops;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
 pub struct TomlData {
     #[serde(default)]
     pub upload: HashMap<String, TomlUpload>,
@@ -20,6 +21,10 @@ pub struct TomlData {
     pub cache_dir: CacheDir,
     #[serde(default)]
     pub cloud_providers: HashMap<String, CloudProviders>,
+    #[serde(default)]
+    pub watch: WatchConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
 }
 
 #[derive(Default, Debug, Deserialize, Serialize, Clone)]
@@ -27,47 +32,435 @@ pub struct CacheDir {
     pub dir: String,
 }
 
+// Settings for `cl_sync watch`
+// quiet_period_secs: how long an entry has to stay unchanged before it gets synced
 #[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WatchConfig {
+    #[serde(default = "default_quiet_period_secs")]
+    pub quiet_period_secs: u64,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            quiet_period_secs: default_quiet_period_secs(),
+        }
+    }
+}
+
+fn default_quiet_period_secs() -> u64 {
+    5
+}
+
+// Settings for `cl_sync --sync`
+// timeout: how long the whole run may take ("2h"), jobs still running are stopped
+// bwlimit: upload limit of the rclone daemon, also for watch and daemon mode,
+// a rate ("512k") or a timetable ("08:00,512k 23:00,off"). rclone has one limit
+// shared by every transfer of the daemon, so there is none per cloud, a cloud
+// can only limit each of its transfers with bwlimit_file.
+// quota_check: what happens when a cloud has less space left than an entry needs
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct SyncConfig {
+    pub timeout: Option<String>,
+    pub bwlimit: Option<String>,
+    #[serde(default)]
+    pub quota_check: QuotaCheck,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaCheck {
+    Off,
+    #[default]
+    Warn,
+    // the entry isn't uploaded to that cloud
+    Abort,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
 pub struct TomlUpload {
     pub file_or_dir_name: String,
     pub file_or_dir_path: String,
-    pub upload_to_clouds: Vec<String>,
+    // cloud names or tables with settings for one cloud only
+    pub upload_to_clouds: Vec<UploadTarget>,
     pub upload_to_cloud_dir: String,
+    // mode of the clouds that are listed by name or without a mode
+    #[serde(default)]
+    pub mode: UploadMode,
+    // which snapshots of mode = "snapshot" are kept on the clouds
+    #[serde(default)]
+    pub retention: Retention,
+    // upload a dir as one compressed tarball instead of file by file
+    pub archive: Option<ArchiveSettings>,
+    // name a single file gets on the clouds, defaults to its local name
+    pub remote_file_name: Option<String>,
+    // rclone settings for the jobs of this entry, e.g. { Transfers = 8, CheckSum = true },
+    // named like in `rclone rc options/get`, win over the ones of the cloud
+    #[serde(default)]
+    pub rclone_options: serde_json::Map<String, serde_json::Value>,
     pub veracrypt_mount_dir: Option<String>,
     pub veracrypt_file_name: Option<String>,
     pub veracrypt_volume_pw: Option<String>,
     pub veracrypt_user_pw: Option<String>,
+    // interval ("10m") or cron expression ("0 3 * * *") for `cl_sync daemon`
+    pub schedule: Option<String>,
+    // how often a failed upload is tried again and the delay before
+    // the first retry ("10s"), doubled on every further retry.
+    // Override the settings of the cloud provider.
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<String>,
+    // how long uploading this entry may take ("30m") before its jobs are stopped
+    pub timeout: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
 pub struct CloudProviders {
     pub cloud_name: String,
     pub dir: String,
+    // base every upload to this cloud goes under ("dge:desk/"), the root when empty
+    #[serde(default)]
     pub paste_to_dir: String,
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<String>,
+    // limit of each single file transfer to this cloud (rclone --bwlimit-file),
+    // same syntax as [sync] bwlimit. Transfers running at the same time each
+    // get it, [sync] bwlimit is what caps them all together.
+    pub bwlimit_file: Option<String>,
+    // rclone settings for every job on this cloud, like rclone_options of an entry
+    #[serde(default)]
+    pub rclone_options: serde_json::Map<String, serde_json::Value>,
+    // settings of the backend of the remote named like its flags,
+    // e.g. { drive_use_trash = false, drive_chunk_size = "64M" }
+    #[serde(default)]
+    pub backend_options: serde_json::Map<String, serde_json::Value>,
+}
+
+// A cloud an entry is uploaded to: "dge", or a table like
+// { cloud = "dge", dir = "Backups/Vault", mode = "copy" }
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum UploadTarget {
+    Cloud(String),
+    Table(TargetSettings),
+}
+
+impl UploadTarget {
+    pub fn cloud(&self) -> &str {
+        match self {
+            UploadTarget::Cloud(cloud) => cloud,
+            UploadTarget::Table(settings) => &settings.cloud,
+        }
+    }
+
+    pub fn settings(&self) -> TargetSettings {
+        match self {
+            UploadTarget::Cloud(cloud) => TargetSettings {
+                cloud: cloud.to_string(),
+                ..Default::default()
+            },
+            UploadTarget::Table(settings) => settings.clone(),
+        }
+    }
+}
+
+impl From<&str> for UploadTarget {
+    fn from(cloud: &str) -> Self {
+        UploadTarget::Cloud(cloud.to_string())
+    }
+}
+
+// Settings of an entry that only apply to one of its clouds
+#[derive(Default, Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TargetSettings {
+    pub cloud: String,
+    // used instead of upload_to_cloud_dir, still under paste_to_dir
+    pub dir: Option<String>,
+    // the mode of the entry when not set
+    pub mode: Option<UploadMode>,
+    // rclone filter rules, e.g. ["- *.tmp", "+ **"]
+    #[serde(default)]
+    pub filters: Vec<String>,
+    // limit of each single file transfer to this cloud, e.g. "1M" (rclone --bwlimit-file)
+    pub bwlimit_file: Option<String>,
+}
+
+// What happens on the cloud: sync makes it identical and deletes
+// files that are gone locally, copy never deletes anything,
+// move deletes the local files once they are uploaded and snapshot
+// copies into a new dir named after the time of every upload
+#[derive(Default, Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadMode {
+    #[default]
+    Sync,
+    Copy,
+    Move,
+    Snapshot,
+}
+
+// Snapshots to keep: the newest keep_last ones and the newest one of each
+// of the last keep_daily days, keep_weekly weeks and keep_monthly months.
+// Every snapshot is kept when none is set, the newest one always is.
+#[derive(Default, Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    pub keep_last: Option<u32>,
+    pub keep_daily: Option<u32>,
+    pub keep_weekly: Option<u32>,
+    pub keep_monthly: Option<u32>,
+}
+
+// name: of the archive on the clouds, <file_or_dir_name>.tar.zst by default
+// level: zstd compression level, from 1 (fastest) to 22 (smallest)
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveSettings {
+    pub name: Option<String>,
+    #[serde(default = "default_compression_level")]
+    pub level: i32,
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+impl ArchiveSettings {
+    pub fn file_name(&self, to_up: &TomlUpload) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("{}.tar.zst", to_up.file_or_dir_name))
+    }
+}
+
+impl Retention {
+    pub fn keeps_all(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl TomlUpload {
+    // Names of the clouds, in the order they are listed
+    pub fn clouds(&self) -> Vec<String> {
+        self.upload_to_clouds
+            .iter()
+            .map(|target| target.cloud().to_string())
+            .collect()
+    }
+
+    // Settings for one cloud, the defaults when it is listed by name
+    pub fn target(&self, cloud: &str) -> TargetSettings {
+        self.upload_to_clouds
+            .iter()
+            .find(|target| target.cloud() == cloud)
+            .map_or_else(
+                || TargetSettings {
+                    cloud: cloud.to_string(),
+                    ..Default::default()
+                },
+                UploadTarget::settings,
+            )
+    }
+
+    // Mode of the uploads to one cloud
+    pub fn mode(&self, cloud: &str) -> UploadMode {
+        self.target(cloud).mode.unwrap_or(self.mode)
+    }
+
+    // Every cloud may only be listed once, results are kept per cloud.
+    // Uploads to the clouds run at the same time, so a move would leave the
+    // other clouds with what was left locally when it got there.
+    fn check_targets(&self, key: &str) -> std::result::Result<(), error::TomlError> {
+        let invalid = |setting, reason: String| error::TomlError::InvalidSetting {
+            section: format!("upload.{}", key),
+            setting,
+            reason,
+        };
+        let clouds = self.clouds();
+        for (index, cloud) in clouds.iter().enumerate() {
+            if clouds[..index].contains(cloud) {
+                return Err(invalid(
+                    "upload_to_clouds",
+                    format!("{} is listed more than once", cloud),
+                ));
+            }
+        }
+        if clouds.len() > 1 {
+            if let Some(cloud) = clouds
+                .iter()
+                .find(|cloud| self.mode(cloud) == UploadMode::Move)
+            {
+                return Err(invalid(
+                    "mode",
+                    format!(
+                        "\"move\" to {} needs it to be the only cloud of the entry",
+                        cloud
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // The archive is copied, moving would leave the dir behind anyway
+    fn check_archive(&self, key: &str) -> std::result::Result<(), error::TomlError> {
+        let Some(archive) = &self.archive else {
+            return Ok(());
+        };
+        let invalid = |setting, reason: &str| error::TomlError::InvalidSetting {
+            section: format!("upload.{}", key),
+            setting,
+            reason: reason.to_string(),
+        };
+        if !(1..=22).contains(&archive.level) {
+            return Err(invalid("archive", "level has to be between 1 and 22"));
+        }
+        if archive
+            .name
+            .as_deref()
+            .is_some_and(|name| name.is_empty() || name.contains('/'))
+        {
+            return Err(invalid("archive", "name has to be a file name"));
+        }
+        if self
+            .clouds()
+            .iter()
+            .any(|cloud| self.mode(cloud) == UploadMode::Move)
+        {
+            return Err(invalid("mode", "\"move\" can't be used with an archive"));
+        }
+        Ok(())
+    }
+}
+
+impl CloudProviders {
+    // Name of the remote in the rclone config
+    pub fn remote_name(&self) -> &str {
+        match self.paste_to_dir.split_once(':') {
+            Some((remote, _)) => remote,
+            None => &self.cloud_name,
+        }
+    }
+
+    // rclone fs paste_to_dir is on and the base path on it,
+    // "dge:desk/" is ("dge:", "desk/"). The backend_options go in its
+    // connection string, "dge,use_trash=false:".
+    pub fn remote_fs(&self) -> (String, &str) {
+        let base = match self.paste_to_dir.split_once(':') {
+            Some((_, base)) => base,
+            None => self.paste_to_dir.as_str(),
+        };
+        let mut fs = self.remote_name().to_string();
+        for (name, value) in &self.backend_options {
+            let name = name.replace('-', "_");
+            // drive_chunk_size is chunk_size of the drive backend
+            let option = name
+                .split_once('_')
+                .map_or(name.as_str(), |(_, option)| option);
+            let value = match value {
+                serde_json::Value::String(value) => value.to_string(),
+                value => value.to_string(),
+            };
+            if value.contains([',', ':', '"']) {
+                fs.push_str(&format!(",{}=\"{}\"", option, value.replace('"', "\"\"")));
+            } else {
+                fs.push_str(&format!(",{}={}", option, value));
+            }
+        }
+        fs.push(':');
+        (fs, base)
+    }
+}
+
+// Where an entry goes on one cloud: the rclone fs of the remote and
+// the path on it, e.g. "dge:" and "desk/OBvault"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    pub fs: String,
+    pub path: String,
+}
+
+impl Destination {
+    // The dir of the entry for this cloud (the dir of its target, else
+    // upload_to_cloud_dir) under the paste_to_dir of the cloud
+    pub fn resolve(to_up: &TomlUpload, provider: &CloudProviders) -> Self {
+        let (fs, base) = provider.remote_fs();
+        let target = to_up.target(&provider.cloud_name);
+        let dir = target.dir.as_ref().unwrap_or(&to_up.upload_to_cloud_dir);
+        Self {
+            fs,
+            path: join_remote_path(base, dir),
+        }
+    }
+
+    // fs and path in one, as sync/sync takes it
+    pub fn remote(&self) -> String {
+        format!("{}{}", self.fs, self.path)
+    }
+
+    // Path of a file inside the destination
+    pub fn file(&self, name: &str) -> String {
+        join_remote_path(&self.path, name)
+    }
+
+    // A dir inside the destination
+    pub fn subdir(&self, name: &str) -> Self {
+        Self {
+            fs: self.fs.to_string(),
+            path: self.file(name),
+        }
+    }
+}
+
+fn join_remote_path(base: &str, path: &str) -> String {
+    [base, path]
+        .iter()
+        .map(|part| part.trim_matches('/'))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("/")
 }
 
 pub enum TomlSection {
     Upload,
     CloudProviders,
     CacheDir,
+    Watch,
+    Sync,
 }
 
 pub enum TomlToParse {
     Upload(HashMap<String, TomlUpload>),
     CloudProviders(HashMap<String, CloudProviders>),
     CacheDir(String),
+    Watch(WatchConfig),
+    Sync(SyncConfig),
+}
+
+// Parse a duration setting like retry_backoff = "10s" or timeout = "1h 30m"
+pub fn parse_duration_setting(
+    section: &str,
+    setting: &'static str,
+    value: &str,
+) -> std::result::Result<Duration, error::TomlError> {
+    humantime::parse_duration(value).map_err(|e| error::TomlError::InvalidSetting {
+        section: section.to_string(),
+        setting,
+        reason: e.to_string(),
+    })
 }
 
 #[derive(Clone)]
 pub struct TomlParser {
     data: TomlData,
+    // --bwlimit of this run, never written back to upload.toml
+    bwlimit: Option<String>,
 }
 
 impl TomlParser {
     // Initializes the struct by parsing the TOML file once
     pub async fn new() -> Result<Self> {
-        let home_path = home_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
-        let config_path = home_path.join(".config/cl_sync/upload.toml");
+        let config_path = sys_ops::toml_file_path();
 
         let toml_data = match fs::read_to_string(&config_path).await {
             Ok(data) => data,
@@ -78,14 +471,33 @@ impl TomlParser {
                     config_path.display()
                 ))?
             }
-            Err(e) => return Err(error::TomlError::FileReadError(e).into()),
+            Err(e) => {
+                return Err(error::TomlError::FileReadError {
+                    path: config_path,
+                    source: e,
+                }
+                .into())
+            }
         };
 
-        let data = toml::from_str(&toml_data).context(format!(
-            "Failed to parse upload.toml file at {}",
-            config_path.display()
-        ))?;
-        Ok(Self { data })
+        let data: TomlData =
+            toml::from_str(&toml_data).map_err(|e| error::TomlError::ParseError {
+                path: config_path,
+                error: e,
+            })?;
+        Self::from_data(data)
+    }
+
+    // Settings already parsed, checked like the ones of the file
+    pub fn from_data(data: TomlData) -> Result<Self> {
+        for (key, to_up) in &data.upload {
+            to_up.check_targets(key)?;
+            to_up.check_archive(key)?;
+        }
+        Ok(Self {
+            data,
+            bwlimit: None,
+        })
     }
 
     pub async fn get_section_from_toml(&self, section: TomlSection) -> Result<TomlToParse> {
@@ -94,9 +506,7 @@ impl TomlParser {
             TomlSection::Upload => {
                 // Check if `upload` is valid
                 if self.data.upload.is_empty() {
-                    Err(anyhow!(
-                        "The 'upload' section is missing or empty in the upload.toml file"
-                    ))
+                    Err(error::TomlError::MissingSection("upload").into())
                 } else {
                     Ok(TomlToParse::Upload(self.data.upload.clone()))
                 }
@@ -104,9 +514,7 @@ impl TomlParser {
             TomlSection::CloudProviders => {
                 // Check if `cloud_providers` is valid
                 if self.data.cloud_providers.is_empty() {
-                    Err(anyhow!(
-                        "The 'cloud_providers' section is missing or empty in the upload.toml file"
-                    ))
+                    Err(error::TomlError::MissingSection("cloud_providers").into())
                 } else {
                     Ok(TomlToParse::CloudProviders(
                         self.data.cloud_providers.clone(),
@@ -116,21 +524,32 @@ impl TomlParser {
             TomlSection::CacheDir => {
                 // Check if `cloud_providers` is valid
                 if self.data.cache_dir.dir.is_empty() {
-                    Err(anyhow!(
-                        "The 'cache_dir.dir' section is missing or empty in the upload.toml file"
-                    ))
+                    Err(error::TomlError::MissingSection("cache_dir.dir").into())
                 } else {
                     Ok(TomlToParse::CacheDir(self.data.cache_dir.dir.clone()))
                 }
             }
+            TomlSection::Watch => Ok(TomlToParse::Watch(self.data.watch.clone())),
+            TomlSection::Sync => {
+                let mut sync = self.data.sync.clone();
+                if let Some(rate) = &self.bwlimit {
+                    sync.bwlimit = Some(rate.to_string());
+                }
+                Ok(TomlToParse::Sync(sync))
+            }
         }
     }
 
+    // --bwlimit wins over [sync] bwlimit for this run
+    pub fn override_bwlimit(&mut self, rate: &str) {
+        self.bwlimit = Some(rate.to_string());
+    }
+
     /// Updates the `[cache_dir] dir` value and writes back to `upload.toml`
     pub async fn update_cache_dir(&mut self) -> Result<()> {
         let home_path = home_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
 
-        let toml_path = home_path.join(".config/cl_sync/upload.toml");
+        let toml_path = sys_ops::toml_file_path();
         let cache_path = home_path.join(".config/cl_sync/cache.bin");
         // Update the `dir` field in memory
         self.data.cache_dir.dir = cache_path.to_string_lossy().to_string();
@@ -156,6 +575,151 @@ impl TomlParser {
 mod toml_parse_test {
     use super::*;
 
+    #[test]
+    fn test_destination_resolve() {
+        let mut provider = CloudProviders {
+            cloud_name: "dge".to_string(),
+            paste_to_dir: "dge:desk/".to_string(),
+            ..Default::default()
+        };
+        let mut to_up = TomlUpload {
+            upload_to_cloud_dir: "OBvault".to_string(),
+            ..Default::default()
+        };
+        let destination = Destination::resolve(&to_up, &provider);
+        assert_eq!(destination.remote(), "dge:desk/OBvault");
+        assert_eq!(destination.file("pw.kdbx"), "desk/OBvault/pw.kdbx");
+
+        to_up.upload_to_clouds = vec![UploadTarget::Table(TargetSettings {
+            cloud: "dge".to_string(),
+            dir: Some("/Backups/Vault/".to_string()),
+            ..Default::default()
+        })];
+        assert_eq!(
+            Destination::resolve(&to_up, &provider).remote(),
+            "dge:desk/Backups/Vault"
+        );
+
+        provider.paste_to_dir = String::new();
+        to_up.upload_to_clouds.clear();
+        assert_eq!(
+            Destination::resolve(&to_up, &provider).remote(),
+            "dge:OBvault"
+        );
+
+        provider.backend_options = serde_json::json!({
+            "drive-use-trash": false,
+            "drive_chunk_size": "64M",
+            "drive_team_drive": "a,b",
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+        assert_eq!(
+            Destination::resolve(&to_up, &provider).remote(),
+            "dge,use_trash=false,chunk_size=64M,team_drive=\"a,b\":OBvault"
+        );
+    }
+
+    #[test]
+    fn test_upload_targets() {
+        let to_up: TomlUpload = toml::from_str(
+            r#"
+            file_or_dir_name = "vault"
+            file_or_dir_path = "/home/user/vault"
+            upload_to_cloud_dir = "Vault"
+            upload_to_clouds = [
+                "dg",
+                { cloud = "dge", dir = "Backups/Vault", mode = "copy", filters = ["- *.tmp"] },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(to_up.clouds(), vec!["dg".to_string(), "dge".to_string()]);
+        assert_eq!(to_up.mode("dg"), UploadMode::Sync);
+        assert_eq!(to_up.mode("dge"), UploadMode::Copy);
+        let dge = to_up.target("dge");
+        assert_eq!(dge.filters, vec!["- *.tmp".to_string()]);
+
+        let provider = CloudProviders {
+            cloud_name: "dge".to_string(),
+            paste_to_dir: "dge:".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Destination::resolve(&to_up, &provider).remote(),
+            "dge:Backups/Vault"
+        );
+
+        let mut snapshots = to_up.clone();
+        snapshots.mode = UploadMode::Snapshot;
+        assert_eq!(snapshots.mode("dg"), UploadMode::Snapshot);
+        assert_eq!(snapshots.mode("dge"), UploadMode::Copy);
+
+        let mut archived = to_up.clone();
+        archived.archive = Some(ArchiveSettings {
+            name: None,
+            level: 19,
+        });
+        assert!(archived.check_archive("vault").is_ok());
+        assert_eq!(
+            archived.archive.as_ref().unwrap().file_name(&archived),
+            "vault.tar.zst"
+        );
+        archived.mode = UploadMode::Move;
+        assert!(archived.check_archive("vault").is_err());
+
+        let mut twice = to_up.clone();
+        twice.upload_to_clouds.push(UploadTarget::from("dg"));
+        assert!(twice.check_targets("vault").is_err());
+
+        // a move only with one cloud, for the entry or a single target
+        let mut moved = to_up.clone();
+        moved.mode = UploadMode::Move;
+        assert!(moved.check_targets("vault").is_err());
+        moved.mode = UploadMode::Sync;
+        moved.upload_to_clouds[1] = UploadTarget::Table(TargetSettings {
+            cloud: "dge".to_string(),
+            mode: Some(UploadMode::Move),
+            ..Default::default()
+        });
+        assert!(moved.check_targets("vault").is_err());
+        moved.upload_to_clouds.remove(0);
+        assert!(moved.check_targets("vault").is_ok());
+
+        let unknown = toml::from_str::<TomlUpload>(
+            r#"
+            file_or_dir_name = "vault"
+            file_or_dir_path = "/home/user/vault"
+            upload_to_cloud_dir = "Vault"
+            upload_to_clouds = [{ cloud = "dge", mdoe = "copy" }]
+            "#,
+        );
+        assert!(unknown.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bwlimit_override() -> Result<()> {
+        let mut parser = TomlParser::from_data(TomlData {
+            sync: SyncConfig {
+                bwlimit: Some("1M".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        parser.override_bwlimit("512k");
+
+        match parser.get_section_from_toml(TomlSection::Sync).await? {
+            TomlToParse::Sync(sync) => assert_eq!(sync.bwlimit.as_deref(), Some("512k")),
+            _ => panic!("Unexpected section returned"),
+        }
+        // what update_cache_dir writes back keeps the limit of the file
+        let written = toml::to_string_pretty(&parser.data)?;
+        assert!(written.contains("bwlimit = \"1M\""));
+        assert!(!written.contains("512k"));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_toml_parsing() -> Result<()> {
         let parser = TomlParser::new().await?;
@@ -0,0 +1,211 @@
+// What the tests of every module build on: upload entries, cloud providers,
+// settings with a cache file and a stub rclone daemon
+use hashbrown::HashMap;
+use serde_json::Value;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::cl_sync::SyncSession;
+use crate::operations::cl_sync_cache::{ClCache, ToUpload};
+use crate::operations::{rc, toml};
+
+// [upload.<name>] for path, named after its file stem and uploaded to "Vault"
+pub fn entry(path: &str, clouds: &[&str]) -> toml::TomlUpload {
+    toml::TomlUpload {
+        file_or_dir_name: Path::new(path)
+            .file_stem()
+            .map_or(String::new(), |name| name.to_string_lossy().to_string()),
+        file_or_dir_path: path.to_string(),
+        upload_to_clouds: clouds
+            .iter()
+            .map(|cloud| toml::UploadTarget::from(*cloud))
+            .collect(),
+        upload_to_cloud_dir: "Vault".to_string(),
+        ..Default::default()
+    }
+}
+
+// [cloud_providers.<cloud>] mounted under ~/Documents/cloud, pasting to "<cloud>:desk/"
+pub fn provider(cloud: &str) -> toml::CloudProviders {
+    toml::CloudProviders {
+        cloud_name: cloud.to_string(),
+        dir: format!("/home/user/Documents/cloud/{}/", cloud),
+        paste_to_dir: format!("{}:desk/", cloud),
+        ..Default::default()
+    }
+}
+
+// Settings with the given upload entries, caching to cache_path
+pub fn parser(entries: &[(&str, toml::TomlUpload)], cache_path: &Path) -> toml::TomlParser {
+    parser_with_clouds(entries, &[], cache_path)
+}
+
+// Settings with the given upload entries and cloud providers
+pub fn parser_with_clouds(
+    entries: &[(&str, toml::TomlUpload)],
+    providers: &[toml::CloudProviders],
+    cache_path: &Path,
+) -> toml::TomlParser {
+    toml::TomlParser::from_data(toml::TomlData {
+        upload: entries
+            .iter()
+            .map(|(key, to_up)| (key.to_string(), to_up.clone()))
+            .collect(),
+        cache_dir: toml::CacheDir {
+            dir: cache_path.to_string_lossy().to_string(),
+        },
+        cloud_providers: providers
+            .iter()
+            .map(|provider| (provider.cloud_name.to_string(), provider.clone()))
+            .collect(),
+        watch: Default::default(),
+        sync: Default::default(),
+    })
+    .unwrap()
+}
+
+// Write a cache file at cache_path holding records
+pub async fn write_cache(cache_path: &Path, records: &[(&str, ToUpload)]) {
+    let cache = ClCache {
+        data: Arc::new(Mutex::new(
+            records
+                .iter()
+                .map(|(key, record)| (key.to_string(), record.clone()))
+                .collect(),
+        )),
+        schedule: Arc::new(Mutex::new(HashMap::new())),
+        cache_storage_path: cache_path.to_string_lossy().to_string(),
+    };
+    cache.save_to_file().await.unwrap();
+}
+
+type Handler = dyn Fn(&str, &Value) -> Value + Send + Sync;
+
+// A stand-in for the rclone daemon on a port of its own, reached through
+// client(). handler answers a command and its parameters with the JSON body,
+// a body with an error and a status fails the call like rclone does.
+pub struct RcStub {
+    calls: Arc<std::sync::Mutex<Vec<(String, Value)>>>,
+    server: JoinHandle<()>,
+    url: String,
+}
+
+impl RcStub {
+    pub async fn start(handler: impl Fn(&str, &Value) -> Value + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let calls = Arc::new(std::sync::Mutex::new(vec![]));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let server_calls = calls.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let calls = server_calls.clone();
+                tokio::spawn(async move {
+                    let _ = answer(stream, &*handler, &calls).await;
+                });
+            }
+        });
+        Self {
+            calls,
+            server,
+            url: format!("http://{}", addr),
+        }
+    }
+
+    pub fn client(&self) -> rc::RcClient {
+        rc::RcClient::new(&self.url)
+    }
+
+    // A session whose calls and jobs go to the stub
+    pub fn session(&self) -> SyncSession {
+        SyncSession::new().with_client(self.client())
+    }
+
+    // Commands called so far, in order
+    pub fn commands(&self) -> Vec<String> {
+        let calls = self.calls.lock().unwrap();
+        calls
+            .iter()
+            .map(|(command, _)| command.to_string())
+            .collect()
+    }
+
+    // Parameters of the calls of command
+    pub fn params(&self, command: &str) -> Vec<Value> {
+        let calls = self.calls.lock().unwrap();
+        calls
+            .iter()
+            .filter(|(called, _)| called == command)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+}
+
+impl Drop for RcStub {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+// One HTTP request per connection, POST /<command> with a JSON body
+async fn answer(
+    mut stream: TcpStream,
+    handler: &Handler,
+    calls: &std::sync::Mutex<Vec<(String, Value)>>,
+) -> std::io::Result<()> {
+    let mut request = vec![];
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&chunk[..read]);
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+    let command = head
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .trim_start_matches('/')
+        .to_string();
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while request.len() < header_end + length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+    let end = request.len().min(header_end + length);
+    let params: Value = serde_json::from_slice(&request[header_end..end]).unwrap_or(Value::Null);
+
+    let body = handler(&command, &params);
+    calls.lock().unwrap().push((command, params));
+    let status = match (body.get("error"), body.get("status")) {
+        (Some(_), Some(status)) => status.as_u64().unwrap_or(500),
+        _ => 200,
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}